@@ -0,0 +1,347 @@
+use super::{ Error, Token, TokenPos };
+use super::parse::{ Node, Program };
+
+// How many values a word (or function) pops and then pushes
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct StackEffect {
+    pub inputs: usize,
+    pub outputs: usize
+}
+impl std::fmt::Display for StackEffect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "({} -- {})", self.inputs, self.outputs)
+    }
+}
+
+fn word_effect(token: &Token) -> StackEffect {
+    let (inputs, outputs) = match token {
+        Token::Num(_) | Token::Str(..) | Token::Loc | Token::Load | Token::Copy => (0, 1),
+        Token::Key | Token::ReadLine => (0, 1),
+        Token::OpAdd | Token::OpSub | Token::OpMul | Token::OpDiv => (2, 1),
+        Token::Eq | Token::GT | Token::LT | Token::And | Token::Or => (2, 1),
+        Token::Not => (1, 1),
+        Token::Print | Token::Write | Token::Emit | Token::Puts => (1, 0),
+        Token::Drop | Token::Store => (1, 0),
+        Token::Up | Token::Down | Token::Left | Token::Right => (1, 0),
+        Token::Dup => (1, 2),
+        Token::Swap => (2, 2),
+        Token::Over => (2, 3),
+        Token::FnCall(_) => unreachable!("Function effects depend on the program."),
+    };
+
+    StackEffect { inputs, outputs }
+}
+
+// Abstract stack, relative to the depth at the start of the body being
+// checked. `min` is the lowest depth reached, i.e. how many values the body
+// consumed from its caller.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct State {
+    depth: isize,
+    min: isize
+}
+
+impl State {
+    fn apply(&mut self, effect: StackEffect) {
+        self.depth -= effect.inputs as isize;
+        self.min = self.min.min(self.depth);
+        self.depth += effect.outputs as isize;
+    }
+}
+
+struct Checker<'a> {
+    // `None` until a function's effect has been inferred
+    effects: &'a [Option<StackEffect>],
+    // Lowest depth allowed before the body underflows, or `None` while
+    // inferring a function's effect.
+    floor: Option<isize>,
+    // Only collected on the final, verifying pass
+    errors: Option<&'a mut Vec<Error>>
+}
+
+impl<'a> Checker<'a> {
+    fn report(&mut self, err: Error) {
+        if let Some(errors) = &mut self.errors {
+            errors.push(err);
+        }
+    }
+
+    fn apply(&mut self, state: &mut State, effect: StackEffect, pos: TokenPos) {
+        if let Some(floor) = self.floor {
+            let available = state.depth - floor;
+
+            if available < effect.inputs as isize {
+                self.report(
+                    Error::new(format!(
+                        "Not enough values on the stack: this word needs {} but only {} {} available.",
+                        effect.inputs, available, if available == 1 { "is" } else { "are" }
+                    ), pos)
+                );
+
+                // Pretend the values were there to avoid cascading errors
+                state.depth = floor + effect.inputs as isize;
+            }
+        }
+
+        state.apply(effect);
+    }
+
+    // Returns `None` if the path depends on a function whose effect is not
+    // known yet (i.e. a recursive call while inferring).
+    fn check_nodes(&mut self, nodes: &[Node], mut state: State) -> Option<State> {
+        for node in nodes {
+            state = match node {
+                Node::Word(Token::FnCall(index), pos) => {
+                    let effect = self.effects[*index]?;
+                    self.apply(&mut state, effect, *pos);
+                    state
+                },
+                Node::Word(token, pos) => {
+                    self.apply(&mut state, word_effect(token), *pos);
+                    state
+                },
+                Node::If(block) => {
+                    self.apply(&mut state, StackEffect { inputs: 1, outputs: 0 }, block.pos);
+
+                    let then_state = self.check_nodes(&block.then_body, state);
+                    let (else_state, else_pos) = match &block.else_body {
+                        Some((else_pos, else_body)) => (self.check_nodes(else_body, state), *else_pos),
+                        None => (Some(state), block.end)
+                    };
+
+                    match (then_state, else_state) {
+                        (Some(a), Some(b)) => {
+                            if a.depth != b.depth {
+                                let msg = if block.else_body.is_some() {
+                                    "The branches of this if block leave different numbers of values on the stack."
+                                } else {
+                                    "An if block without `else` must leave the stack depth unchanged."
+                                };
+
+                                self.report(
+                                    Error::new(msg, block.pos)
+                                        .with_note(format!("The `if` branch changes the stack depth by {}.", a.depth - state.depth))
+                                        .with_note(format!("The {} changes it by {} (at {}).",
+                                            if block.else_body.is_some() { "`else` branch" } else { "skipped branch" },
+                                            b.depth - state.depth, else_pos))
+                                );
+                            }
+
+                            State { depth: a.depth, min: a.min.min(b.min) }
+                        },
+                        (Some(s), None) | (None, Some(s)) => s,
+                        (None, None) => return None
+                    }
+                },
+                Node::While(block) => {
+                    let mut cond_state = self.check_nodes(&block.cond, state)?;
+                    self.apply(&mut cond_state, StackEffect { inputs: 1, outputs: 0 }, block.do_pos);
+
+                    if cond_state.depth != state.depth {
+                        self.report(
+                            Error::new("The condition of a while loop must push exactly one value.", block.pos)
+                                .with_note(format!("The condition changes the stack depth by {} before `do`.",
+                                    cond_state.depth - state.depth + 1))
+                        );
+                    }
+
+                    let exit = State { depth: state.depth, min: cond_state.min };
+
+                    match self.check_nodes(&block.body, exit) {
+                        Some(body_state) => {
+                            if body_state.depth != state.depth {
+                                self.report(
+                                    Error::new("The body of a while loop must leave the stack depth unchanged.", block.pos)
+                                        .with_note(format!("The body changes the stack depth by {}.", body_state.depth - state.depth))
+                                );
+                            }
+
+                            State { depth: state.depth, min: exit.min.min(body_state.min) }
+                        },
+                        None => exit
+                    }
+                }
+            };
+        }
+
+        Some(state)
+    }
+}
+
+// Checks that no word can underflow the stack, that both branches of an `if`
+// agree and that loops are stack-neutral. Functions with a declared signature
+// are checked against it; the others have their effect inferred. Returns the
+// effect of every function, indexed like `Program::functions`.
+pub fn check_program(program: &Program) -> Result<Vec<StackEffect>, Vec<Error>> {
+    let mut effects: Vec<Option<StackEffect>> = program.functions.iter()
+        .map(|function| function.signature.map(|(effect, _)| effect))
+        .collect();
+
+    // Functions may call each other in any order, so infer effects until no
+    // more can be resolved. Recursive calls are skipped in favor of the
+    // branch that terminates.
+    loop {
+        let mut changed = false;
+
+        for (index, function) in program.functions.iter().enumerate() {
+            if effects[index].is_some() {
+                continue;
+            }
+
+            let mut checker = Checker { effects: &effects, floor: None, errors: None };
+            if let Some(state) = checker.check_nodes(&function.body, State { depth: 0, min: 0 }) {
+                effects[index] = Some(StackEffect {
+                    inputs: (-state.min) as usize,
+                    outputs: (state.depth - state.min) as usize
+                });
+                changed = true;
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    let mut errors: Vec<Error> = Vec::new();
+
+    for (index, function) in program.functions.iter().enumerate() {
+        match effects[index] {
+            Some(effect) => {
+                let mut checker = Checker { effects: &effects, floor: Some(-(effect.inputs as isize)), errors: Some(&mut errors) };
+                let state = checker.check_nodes(&function.body, State { depth: 0, min: 0 });
+
+                if let (Some(state), Some((declared, sig_pos))) = (state, function.signature) {
+                    let outputs = state.depth + declared.inputs as isize;
+
+                    if outputs != declared.outputs as isize {
+                        errors.push(
+                            Error::new(format!(
+                                "Function `{}` leaves {} value(s) on the stack, but its signature declares {}.",
+                                function.name, outputs, declared.outputs
+                            ), function.end)
+                                .with_note(format!("`{}` is declared as {} at {}.", function.name, declared, sig_pos))
+                        );
+                    }
+                }
+            },
+            None => errors.push(
+                Error::new(format!("Unable to infer the stack effect of `{}`.", function.name), function.pos)
+                    .with_note("Every path through the function recurses, so it can never return.")
+            )
+        }
+    }
+
+    let mut checker = Checker { effects: &effects, floor: Some(0), errors: Some(&mut errors) };
+    checker.check_nodes(&program.body, State { depth: 0, min: 0 });
+
+    if errors.is_empty() {
+        Ok(effects.into_iter().map(|e| e.unwrap_or_default()).collect())
+    } else {
+        errors.sort_by_key(|err| err.pos().map(|pos| (pos.file, pos.offset)));
+
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_program;
+
+    fn check(src: &str) -> Result<Vec<StackEffect>, Vec<(String, usize, usize)>> {
+        let (_, program) = test_program(src);
+
+        check_program(&program).map_err(|errors| {
+            errors.iter().map(|err| {
+                let pos = err.pos().unwrap();
+                (err.msg().to_string(), pos.line, pos.col)
+            }).collect()
+        })
+    }
+
+    fn effect(inputs: usize, outputs: usize) -> StackEffect {
+        StackEffect { inputs, outputs }
+    }
+
+    #[test]
+    fn underflow() {
+        assert_eq!(check("1 print\n1 +"), Err(vec![
+            (String::from("Not enough values on the stack: this word needs 2 but only 1 is available."), 2, 3)
+        ]));
+        assert_eq!(check("drop"), Err(vec![
+            (String::from("Not enough values on the stack: this word needs 1 but only 0 are available."), 1, 1)
+        ]));
+    }
+
+    #[test]
+    fn if_branches_must_agree() {
+        assert_eq!(check("1 if 2 else 3 4 end"), Err(vec![
+            (String::from("The branches of this if block leave different numbers of values on the stack."), 1, 3)
+        ]));
+        assert_eq!(check("1\n1 if 2 end"), Err(vec![
+            (String::from("An if block without `else` must leave the stack depth unchanged."), 2, 3)
+        ]));
+        assert!(check("1 if 2 else 3 end print").is_ok());
+    }
+
+    #[test]
+    fn while_must_be_stack_neutral() {
+        assert_eq!(check("0 while dup 10 < do dup 1 + end"), Err(vec![
+            (String::from("The body of a while loop must leave the stack depth unchanged."), 1, 3)
+        ]));
+        assert_eq!(check("0 while dup dup do end"), Err(vec![
+            (String::from("The condition of a while loop must push exactly one value."), 1, 3)
+        ]));
+        assert!(check("0 while dup 10 < do 1 + end drop").is_ok());
+    }
+
+    #[test]
+    fn infers_recursive_functions() {
+        let src = "\
+fn fact
+    dup 1 > if dup 1 - fact * end
+end
+5 fact print";
+        assert_eq!(check(src), Ok(vec![effect(1, 1)]));
+    }
+
+    #[test]
+    fn infers_mutually_recursive_functions() {
+        let src = "\
+fn even
+    dup 0 = if drop 1 else 1 - odd end
+end
+fn odd
+    dup 0 = if drop 0 else 1 - even end
+end
+7 even print";
+        assert_eq!(check(src), Ok(vec![effect(1, 1), effect(1, 1)]));
+    }
+
+    #[test]
+    fn function_that_always_recurses() {
+        assert_eq!(check("fn forever\n    forever\nend"), Err(vec![
+            (String::from("Unable to infer the stack effect of `forever`."), 1, 1)
+        ]));
+    }
+
+    #[test]
+    fn signature_mismatch() {
+        assert_eq!(check("fn two int -- int\n    dup\nend\n1 two print"), Err(vec![
+            (String::from("Function `two` leaves 2 value(s) on the stack, but its signature declares 1."), 3, 1)
+        ]));
+        // The body can only use the inputs it declares
+        assert_eq!(check("fn add int -- int\n    +\nend\n1 2 add print"), Err(vec![
+            (String::from("Not enough values on the stack: this word needs 2 but only 1 is available."), 2, 5)
+        ]));
+    }
+
+    #[test]
+    fn signature_sets_the_effect_of_calls() {
+        assert_eq!(check("fn five -- int\n    5\nend\nfive five + print"), Ok(vec![effect(0, 1)]));
+        assert_eq!(check("fn five -- int\n    5\nend\nfive + print"), Err(vec![
+            (String::from("Not enough values on the stack: this word needs 2 but only 1 is available."), 4, 6)
+        ]));
+    }
+}
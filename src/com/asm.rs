@@ -0,0 +1,860 @@
+// Built-in assembler for the subset of NASM syntax emitted by `com::compile`,
+// encoding x86_64 machine code without shelling out to `nasm`.
+
+use std::collections::{ HashMap, HashSet };
+use std::convert::TryFrom;
+
+use crate::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Section {
+    Text,
+    Data,
+    Bss,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FixupKind {
+    // 32-bit displacement relative to the end of the instruction at `next`
+    Rel32 { next: usize },
+    // 32-bit absolute address (sign-extended by the CPU)
+    Abs32,
+}
+
+#[derive(Debug, Clone)]
+struct Fixup {
+    offset: usize,
+    target: String,
+    kind: FixupKind
+}
+
+// A reference from `.text` into one of the sections, for relocatable objects
+#[derive(Debug, Clone, Copy)]
+pub struct Relocation {
+    pub offset: usize,
+    pub section: Section,
+    pub addend: i64,
+    pub pc_relative: bool
+}
+
+// Assembled sections with their symbols. Addresses are only known once the
+// sections are laid out, so references are kept as fixups until `link`.
+#[derive(Debug, Default)]
+pub struct Object {
+    pub text: Vec<u8>,
+    pub data: Vec<u8>,
+    pub bss_len: usize,
+    symbols: HashMap<String, (Section, usize)>,
+    globals: HashSet<String>,
+    fixups: Vec<Fixup>
+}
+
+impl Object {
+    pub fn symbol(&self, name: &str) -> Option<(Section, usize)> {
+        self.symbols.get(name).copied()
+    }
+
+    // Every symbol as `(name, section, offset, global)`, in address order
+    pub fn symbols(&self) -> Vec<(&str, Section, usize, bool)> {
+        let mut symbols: Vec<(&str, Section, usize, bool)> = self.symbols.iter()
+            .map(|(name, (section, offset))| (name.as_str(), *section, *offset, self.globals.contains(name)))
+            .collect();
+        symbols.sort_by_key(|(name, section, offset, _)| (*section as u8, *offset, *name));
+
+        symbols
+    }
+
+    // The fixups expressed relative to the start of the section they refer to,
+    // with the addend following the ELF convention (`S + A - P`)
+    pub fn relocations(&self) -> Result<Vec<Relocation>, Error> {
+        self.fixups.iter().map(|fixup| {
+            let (section, offset) = self.symbols.get(&fixup.target).copied().ok_or_else(|| {
+                Error::without_pos(format!("Undefined symbol `{}` in generated assembly.", fixup.target))
+            })?;
+
+            Ok(match fixup.kind {
+                FixupKind::Rel32 { next } => Relocation {
+                    offset: fixup.offset,
+                    section,
+                    addend: offset as i64 - (next - fixup.offset) as i64,
+                    pc_relative: true
+                },
+                FixupKind::Abs32 => Relocation { offset: fixup.offset, section, addend: offset as i64, pc_relative: false }
+            })
+        }).collect()
+    }
+
+    // Patches every reference in `.text` given the address of each section
+    pub fn link(&mut self, text_addr: u64, data_addr: u64, bss_addr: u64) -> Result<(), Error> {
+        for fixup in &self.fixups {
+            let (section, offset) = self.symbols.get(&fixup.target).copied().ok_or_else(|| {
+                Error::without_pos(format!("Undefined symbol `{}` in generated assembly.", fixup.target))
+            })?;
+
+            let target = match section {
+                Section::Text => text_addr,
+                Section::Data => data_addr,
+                Section::Bss => bss_addr,
+            } + offset as u64;
+
+            let value = match fixup.kind {
+                FixupKind::Rel32 { next } => target as i64 - (text_addr + next as u64) as i64,
+                FixupKind::Abs32 => target as i64,
+            };
+
+            let value = i32::try_from(value).map_err(|_| {
+                Error::without_pos(format!("Symbol `{}` is out of range of a 32-bit reference.", fixup.target))
+            })?;
+
+            self.text[fixup.offset..fixup.offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Reg {
+    num: u8,
+    size: u8
+}
+
+impl Reg {
+    // spl, bpl, sil and dil can only be encoded with a REX prefix
+    fn needs_rex(&self) -> bool {
+        self.size == 1 && (4..8).contains(&self.num)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Mem {
+    base: Option<Reg>,
+    index: Option<(Reg, u8)>,
+    disp: i64,
+    label: Option<String>,
+    size: Option<u8>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Reg(Reg),
+    Imm(i64),
+    // Address of a symbol, either as an immediate or a branch target
+    Label(String),
+    Mem(Mem),
+}
+
+const REGS_64: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi",
+    "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"
+];
+const REGS_32: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi",
+    "r8d", "r9d", "r10d", "r11d", "r12d", "r13d", "r14d", "r15d"
+];
+const REGS_16: [&str; 16] = [
+    "ax", "cx", "dx", "bx", "sp", "bp", "si", "di",
+    "r8w", "r9w", "r10w", "r11w", "r12w", "r13w", "r14w", "r15w"
+];
+const REGS_8: [&str; 16] = [
+    "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil",
+    "r8b", "r9b", "r10b", "r11b", "r12b", "r13b", "r14b", "r15b"
+];
+
+fn parse_reg(s: &str) -> Option<Reg> {
+    for (names, size) in [(&REGS_64, 8), (&REGS_32, 4), (&REGS_16, 2), (&REGS_8, 1)] {
+        if let Some(num) = names.iter().position(|r| *r == s) {
+            return Some(Reg { num: num as u8, size });
+        }
+    }
+
+    None
+}
+
+fn parse_int(s: &str) -> Option<i64> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s)
+    };
+
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<u64>().ok()?
+    } as i64;
+
+    Some(if negative { value.wrapping_neg() } else { value })
+}
+
+fn is_symbol(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        && !s.starts_with(|c: char| c.is_ascii_digit())
+}
+
+fn parse_mem(s: &str, size: Option<u8>, scope: &str) -> Result<Mem, String> {
+    let mut mem = Mem { base: None, index: None, disp: 0, label: None, size };
+
+    // Split into signed terms: `rsp+32-8` -> [+rsp, +32, -8]
+    let mut terms: Vec<(bool, String)> = Vec::new();
+    let mut current = String::new();
+    let mut negative = false;
+    for c in s.chars().filter(|c| !c.is_whitespace()) {
+        if (c == '+' || c == '-') && !current.is_empty() {
+            terms.push((negative, std::mem::take(&mut current)));
+            negative = c == '-';
+        } else if c == '-' {
+            negative = !negative;
+        } else if c != '+' {
+            current.push(c);
+        }
+    }
+    terms.push((negative, current));
+
+    for (negative, term) in terms {
+        if let Some((reg, scale)) = term.split_once('*') {
+            let reg = parse_reg(reg).ok_or_else(|| format!("invalid index register `{}`", reg))?;
+            let scale = match scale {
+                "1" | "2" | "4" | "8" => scale.parse().unwrap(),
+                _ => return Err(format!("invalid scale `{}`", scale))
+            };
+            if negative || mem.index.is_some() {
+                return Err(format!("invalid memory operand `[{}]`", s));
+            }
+            mem.index = Some((reg, scale));
+        } else if let Some(reg) = parse_reg(&term) {
+            if negative {
+                return Err(format!("invalid memory operand `[{}]`", s));
+            }
+            if mem.base.is_none() {
+                mem.base = Some(reg);
+            } else if mem.index.is_none() {
+                mem.index = Some((reg, 1));
+            } else {
+                return Err(format!("too many registers in `[{}]`", s));
+            }
+        } else if let Some(value) = parse_int(&term) {
+            mem.disp += if negative { -value } else { value };
+        } else if is_symbol(&term) && !negative && mem.label.is_none() {
+            mem.label = Some(qualify(&term, scope));
+        } else {
+            return Err(format!("invalid memory operand `[{}]`", s));
+        }
+    }
+
+    if let Some((index, _)) = mem.index {
+        if index.num == 4 {
+            return Err("rsp cannot be used as an index register".into());
+        }
+    }
+
+    Ok(mem)
+}
+
+// Local labels (`.loop`) belong to the most recent global label
+fn qualify(name: &str, scope: &str) -> String {
+    if name.starts_with('.') {
+        format!("{}{}", scope, name)
+    } else {
+        name.to_string()
+    }
+}
+
+fn parse_operand(s: &str, scope: &str) -> Result<Operand, String> {
+    let mut s = s.trim();
+    let mut size = None;
+
+    for (prefix, bytes) in [("BYTE ", 1), ("WORD ", 2), ("DWORD ", 4), ("QWORD ", 8)] {
+        if let Some(rest) = s.strip_prefix(prefix) {
+            size = Some(bytes);
+            s = rest.trim();
+        }
+    }
+
+    if let Some(inner) = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+        return Ok(Operand::Mem(parse_mem(inner, size, scope)?));
+    }
+
+    if let Some(reg) = parse_reg(s) {
+        return Ok(Operand::Reg(reg));
+    }
+
+    if let Some(value) = parse_int(s) {
+        return Ok(Operand::Imm(value));
+    }
+
+    if is_symbol(s) {
+        return Ok(Operand::Label(qualify(s, scope)));
+    }
+
+    Err(format!("invalid operand `{}`", s))
+}
+
+fn condition_code(cc: &str) -> Option<u8> {
+    let code = match cc {
+        "o" => 0x0,
+        "no" => 0x1,
+        "b" | "c" | "nae" => 0x2,
+        "ae" | "nb" | "nc" => 0x3,
+        "e" | "z" => 0x4,
+        "ne" | "nz" => 0x5,
+        "be" | "na" => 0x6,
+        "a" | "nbe" => 0x7,
+        "s" => 0x8,
+        "ns" => 0x9,
+        "p" | "pe" => 0xA,
+        "np" | "po" => 0xB,
+        "l" | "nge" => 0xC,
+        "ge" | "nl" => 0xD,
+        "le" | "ng" => 0xE,
+        "g" | "nle" => 0xF,
+        _ => return None
+    };
+
+    Some(code)
+}
+
+fn fits_i8(value: i64) -> bool {
+    i8::try_from(value).is_ok()
+}
+
+fn fits_i32(value: i64) -> bool {
+    i32::try_from(value).is_ok()
+}
+
+// A single encoded instruction, possibly referencing a symbol
+#[derive(Default)]
+struct Inst {
+    bytes: Vec<u8>,
+    fixup: Option<(usize, String, bool)>
+}
+
+impl Inst {
+    // Emits `[66] [REX] opcode ModRM [SIB] [disp]` for a register or memory
+    // operand `rm` and the register (or opcode extension) `reg`.
+    fn encode_rm(&mut self, opcode: &[u8], size: u8, reg: u8, force_rex: bool, rm: &Operand) -> Result<(), String> {
+        let mut rex: u8 = 0;
+        if size == 8 {
+            rex |= 0b1000;
+        }
+        if reg >= 8 {
+            rex |= 0b0100;
+        }
+
+        let mut modrm_bytes: Vec<u8> = Vec::new();
+        let mut rip_label = None;
+
+        match rm {
+            Operand::Reg(r) => {
+                if r.num >= 8 {
+                    rex |= 0b0001;
+                }
+                modrm_bytes.push(0b11_000_000 | ((reg & 7) << 3) | (r.num & 7));
+            },
+            Operand::Mem(mem) => {
+                match (&mem.label, mem.base, mem.index) {
+                    (Some(label), None, None) => {
+                        // RIP-relative
+                        modrm_bytes.push(((reg & 7) << 3) | 0b101);
+                        rip_label = Some((modrm_bytes.len(), label.clone()));
+                        modrm_bytes.extend_from_slice(&(mem.disp as i32).to_le_bytes());
+                    },
+                    (Some(_), _, _) => return Err("symbols cannot be combined with registers in memory operands".into()),
+                    (None, None, _) => return Err("memory operands need a base register".into()),
+                    (None, Some(base), index) => {
+                        if base.num >= 8 {
+                            rex |= 0b0001;
+                        }
+
+                        // rbp and r13 as a base always need a displacement
+                        let mode = if mem.disp == 0 && base.num & 7 != 5 {
+                            0b00
+                        } else if fits_i8(mem.disp) {
+                            0b01
+                        } else if fits_i32(mem.disp) {
+                            0b10
+                        } else {
+                            return Err("displacement does not fit in 32 bits".into());
+                        };
+
+                        match index {
+                            Some((index, scale)) => {
+                                if index.num >= 8 {
+                                    rex |= 0b0010;
+                                }
+                                let scale_bits = match scale { 1 => 0, 2 => 1, 4 => 2, _ => 3 };
+                                modrm_bytes.push((mode << 6) | ((reg & 7) << 3) | 0b100);
+                                modrm_bytes.push((scale_bits << 6) | ((index.num & 7) << 3) | (base.num & 7));
+                            },
+                            None if base.num & 7 == 4 => {
+                                // rsp and r12 as a base need a SIB byte
+                                modrm_bytes.push((mode << 6) | ((reg & 7) << 3) | 0b100);
+                                modrm_bytes.push(0b00_100_100);
+                            },
+                            None => {
+                                modrm_bytes.push((mode << 6) | ((reg & 7) << 3) | (base.num & 7));
+                            }
+                        }
+
+                        match mode {
+                            0b01 => modrm_bytes.push(mem.disp as i8 as u8),
+                            0b10 => modrm_bytes.extend_from_slice(&(mem.disp as i32).to_le_bytes()),
+                            _ => { }
+                        }
+                    }
+                }
+            },
+            _ => return Err("expected a register or memory operand".into())
+        }
+
+        if size == 2 {
+            self.bytes.push(0x66);
+        }
+        if rex != 0 || force_rex {
+            self.bytes.push(0x40 | rex);
+        }
+        self.bytes.extend_from_slice(opcode);
+
+        if let Some((offset, label)) = rip_label {
+            self.fixup = Some((self.bytes.len() + offset, label, true));
+        }
+        self.bytes.extend_from_slice(&modrm_bytes);
+
+        Ok(())
+    }
+
+    // Emits an opcode with the register number in its low 3 bits (`push`, `pop`, ...)
+    fn encode_plus_reg(&mut self, opcode: u8, reg: Reg, wide: bool) {
+        let mut rex = 0;
+        if wide {
+            rex |= 0b1000;
+        }
+        if reg.num >= 8 {
+            rex |= 0b0001;
+        }
+        if rex != 0 || reg.needs_rex() {
+            self.bytes.push(0x40 | rex);
+        }
+        self.bytes.push(opcode + (reg.num & 7));
+    }
+
+    fn imm(&mut self, value: i64, width: u8) {
+        match width {
+            1 => self.bytes.push(value as i8 as u8),
+            2 => self.bytes.extend_from_slice(&(value as i16).to_le_bytes()),
+            4 => self.bytes.extend_from_slice(&(value as i32).to_le_bytes()),
+            _ => self.bytes.extend_from_slice(&value.to_le_bytes()),
+        }
+    }
+
+    fn branch(&mut self, opcode: &[u8], target: &Operand) -> Result<(), String> {
+        match target {
+            Operand::Label(label) => {
+                self.bytes.extend_from_slice(opcode);
+                self.fixup = Some((self.bytes.len(), label.clone(), true));
+                self.bytes.extend_from_slice(&[0; 4]);
+                Ok(())
+            },
+            _ => Err("branch targets must be labels".into())
+        }
+    }
+}
+
+fn operand_size(dst: &Operand, src: Option<&Operand>) -> Result<u8, String> {
+    let size_of = |op: &Operand| match op {
+        Operand::Reg(r) => Some(r.size),
+        Operand::Mem(m) => m.size,
+        _ => None
+    };
+
+    size_of(dst)
+        .or_else(|| src.and_then(size_of))
+        .ok_or_else(|| "operation size not specified".into())
+}
+
+fn force_rex(ops: &[&Operand]) -> bool {
+    ops.iter().any(|op| matches!(op, Operand::Reg(r) if r.needs_rex()))
+}
+
+// Encodes the classic two-operand ALU instructions (add, or, and, sub, xor, cmp)
+fn encode_alu(inst: &mut Inst, base: u8, ext: u8, dst: &Operand, src: &Operand) -> Result<(), String> {
+    let size = operand_size(dst, Some(src))?;
+    let byte = if size == 1 { 0 } else { 1 };
+    let force = force_rex(&[dst, src]);
+
+    match (dst, src) {
+        (_, Operand::Reg(r)) => inst.encode_rm(&[base + byte], size, r.num, force, dst),
+        (Operand::Reg(r), Operand::Mem(_)) => inst.encode_rm(&[base + 2 + byte], size, r.num, force, src),
+        (_, Operand::Imm(value)) => {
+            if size == 1 {
+                inst.encode_rm(&[0x80], size, ext, force, dst)?;
+                inst.imm(*value, 1);
+            } else if fits_i8(*value) {
+                inst.encode_rm(&[0x83], size, ext, force, dst)?;
+                inst.imm(*value, 1);
+            } else if fits_i32(*value) {
+                inst.encode_rm(&[0x81], size, ext, force, dst)?;
+                inst.imm(*value, if size == 2 { 2 } else { 4 });
+            } else {
+                return Err("immediate does not fit in 32 bits".into());
+            }
+            Ok(())
+        },
+        _ => Err("invalid combination of operands".into())
+    }
+}
+
+fn encode_mov(inst: &mut Inst, dst: &Operand, src: &Operand) -> Result<(), String> {
+    let size = operand_size(dst, Some(src))?;
+    let byte = if size == 1 { 0 } else { 1 };
+    let force = force_rex(&[dst, src]);
+
+    match (dst, src) {
+        (_, Operand::Reg(r)) => inst.encode_rm(&[0x88 + byte], size, r.num, force, dst),
+        (Operand::Reg(r), Operand::Mem(_)) => inst.encode_rm(&[0x8A + byte], size, r.num, force, src),
+        (Operand::Reg(r), Operand::Imm(value)) if size == 8 && !fits_i32(*value) => {
+            inst.encode_plus_reg(0xB8, *r, true);
+            inst.imm(*value, 8);
+            Ok(())
+        },
+        (Operand::Reg(r), Operand::Imm(value)) if size != 8 => {
+            inst.encode_plus_reg(if size == 1 { 0xB0 } else { 0xB8 }, *r, false);
+            inst.imm(*value, size);
+            Ok(())
+        },
+        (_, Operand::Imm(value)) => {
+            inst.encode_rm(&[0xC6 + byte], size, 0, force, dst)?;
+            inst.imm(*value, size.min(4));
+            Ok(())
+        },
+        (Operand::Reg(_), Operand::Label(label)) => {
+            // Sign-extended 32-bit absolute address
+            inst.encode_rm(&[0xC7], size, 0, force, dst)?;
+            inst.fixup = Some((inst.bytes.len(), label.clone(), false));
+            inst.imm(0, 4);
+            Ok(())
+        },
+        _ => Err("invalid combination of operands".into())
+    }
+}
+
+fn encode(mnemonic: &str, ops: &[Operand]) -> Result<Inst, String> {
+    let mut inst = Inst::default();
+
+    let alu = |m: &str| match m {
+        "add" => Some((0x00, 0)),
+        "or" => Some((0x08, 1)),
+        "and" => Some((0x20, 4)),
+        "sub" => Some((0x28, 5)),
+        "xor" => Some((0x30, 6)),
+        "cmp" => Some((0x38, 7)),
+        _ => None
+    };
+    // Single-operand group 3/5 instructions with their opcode extension
+    let unary = |m: &str| match m {
+        "not" => Some((0xF6, 2)),
+        "neg" => Some((0xF6, 3)),
+        "mul" => Some((0xF6, 4)),
+        "imul" => Some((0xF6, 5)),
+        "div" => Some((0xF6, 6)),
+        "idiv" => Some((0xF6, 7)),
+        "inc" => Some((0xFE, 0)),
+        "dec" => Some((0xFE, 1)),
+        _ => None
+    };
+    let shift = |m: &str| match m {
+        "shl" | "sal" => Some(4),
+        "shr" => Some(5),
+        "sar" => Some(7),
+        _ => None
+    };
+
+    match (mnemonic, ops) {
+        ("ret", []) => inst.bytes.push(0xC3),
+        ("syscall", []) => inst.bytes.extend_from_slice(&[0x0F, 0x05]),
+        (m, [dst, src]) if alu(m).is_some() => {
+            let (base, ext) = alu(m).unwrap();
+            encode_alu(&mut inst, base, ext, dst, src)?;
+        },
+        ("mov", [dst, src]) => encode_mov(&mut inst, dst, src)?,
+        ("test", [dst, Operand::Reg(r)]) => {
+            let size = operand_size(dst, Some(&ops[1]))?;
+            inst.encode_rm(&[if size == 1 { 0x84 } else { 0x85 }], size, r.num, force_rex(&[dst, &ops[1]]), dst)?;
+        },
+        ("test", [dst, Operand::Imm(value)]) => {
+            let size = operand_size(dst, None)?;
+            inst.encode_rm(&[if size == 1 { 0xF6 } else { 0xF7 }], size, 0, force_rex(&[dst]), dst)?;
+            inst.imm(*value, size.min(4));
+        },
+        (m, [dst]) if unary(m).is_some() => {
+            let (opcode, ext) = unary(m).unwrap();
+            let size = operand_size(dst, None)?;
+            inst.encode_rm(&[if size == 1 { opcode } else { opcode + 1 }], size, ext, force_rex(&[dst]), dst)?;
+        },
+        (m, [dst, Operand::Imm(count)]) if shift(m).is_some() => {
+            let size = operand_size(dst, None)?;
+            inst.encode_rm(&[if size == 1 { 0xC0 } else { 0xC1 }], size, shift(m).unwrap(), force_rex(&[dst]), dst)?;
+            inst.imm(*count, 1);
+        },
+        ("lea", [Operand::Reg(r), src @ Operand::Mem(_)]) => {
+            inst.encode_rm(&[0x8D], r.size, r.num, false, src)?;
+        },
+        ("movzx", [Operand::Reg(r), src]) => {
+            let opcode = match operand_size(src, None)? {
+                1 => 0xB6,
+                2 => 0xB7,
+                _ => return Err("movzx needs a byte or word source".into())
+            };
+            inst.encode_rm(&[0x0F, opcode], r.size, r.num, force_rex(&[src]), src)?;
+        },
+        (m, [Operand::Reg(r), src]) if m.starts_with("cmov") => {
+            let cc = condition_code(&m[4..]).ok_or("unknown condition")?;
+            inst.encode_rm(&[0x0F, 0x40 + cc], r.size, r.num, false, src)?;
+        },
+        (m, [dst]) if m.starts_with("set") => {
+            let cc = condition_code(&m[3..]).ok_or("unknown instruction")?;
+            if operand_size(dst, None)? != 1 {
+                return Err("setcc needs a byte operand".into());
+            }
+            inst.encode_rm(&[0x0F, 0x90 + cc], 1, 0, force_rex(&[dst]), dst)?;
+        },
+        ("push", [Operand::Reg(r)]) => inst.encode_plus_reg(0x50, *r, false),
+        ("push", [Operand::Imm(value)]) => {
+            if fits_i8(*value) {
+                inst.bytes.push(0x6A);
+                inst.imm(*value, 1);
+            } else if fits_i32(*value) {
+                inst.bytes.push(0x68);
+                inst.imm(*value, 4);
+            } else {
+                return Err("immediate does not fit in 32 bits".into());
+            }
+        },
+        ("push", [src @ Operand::Mem(_)]) => inst.encode_rm(&[0xFF], 4, 6, false, src)?,
+        ("pop", [Operand::Reg(r)]) => inst.encode_plus_reg(0x58, *r, false),
+        ("jmp", [target]) => inst.branch(&[0xE9], target)?,
+        ("call", [target]) => inst.branch(&[0xE8], target)?,
+        (m, [target]) if m.starts_with('j') => {
+            let cc = condition_code(&m[1..]).ok_or("unknown instruction")?;
+            inst.branch(&[0x0F, 0x80 + cc], target)?;
+        },
+        _ => return Err("unsupported instruction or operands".into())
+    }
+
+    Ok(inst)
+}
+
+pub fn assemble(lines: &[String]) -> Result<Object, Error> {
+    let mut object = Object::default();
+    let mut section = Section::Text;
+    let mut scope = String::new();
+
+    for (number, line) in lines.iter().enumerate() {
+        let fail = |msg: String| Error::without_pos(format!("Assembler error on line {} (`{}`): {}", number + 1, line.trim(), msg));
+
+        let mut rest = line.split(';').next().unwrap_or("").trim();
+        if rest.is_empty() {
+            continue;
+        }
+
+        if let Some(name) = rest.strip_prefix("section ") {
+            section = match name.trim() {
+                ".text" => Section::Text,
+                ".data" => Section::Data,
+                ".bss" => Section::Bss,
+                name => return Err(fail(format!("unknown section `{}`", name)))
+            };
+            continue;
+        }
+
+        // Symbols are resolved within the object; `extern` ones must be
+        // defined by the runtime, which `link` checks
+        if rest.starts_with("extern ") {
+            continue;
+        }
+
+        if let Some(name) = rest.strip_prefix("global ") {
+            object.globals.insert(name.trim().to_string());
+            continue;
+        }
+
+        let offset = match section {
+            Section::Text => object.text.len(),
+            Section::Data => object.data.len(),
+            Section::Bss => object.bss_len,
+        };
+
+        // `label:` (optionally followed by more on the same line) or, in
+        // `.bss`, `name resq N`
+        let first = rest.split_whitespace().next().unwrap_or("");
+        let label = if let Some(label) = first.strip_suffix(':') {
+            rest = rest[first.len()..].trim();
+            Some(label)
+        } else if section == Section::Bss && is_symbol(first) && !first.starts_with("res") {
+            rest = rest[first.len()..].trim();
+            Some(first)
+        } else {
+            None
+        };
+
+        if let Some(label) = label {
+            if !is_symbol(label) {
+                return Err(fail(format!("invalid label `{}`", label)));
+            }
+
+            let name = qualify(label, &scope);
+            if !label.starts_with('.') {
+                scope = label.to_string();
+            }
+
+            if object.symbols.insert(name.clone(), (section, offset)).is_some() {
+                return Err(fail(format!("symbol `{}` is defined more than once", name)));
+            }
+        }
+
+        if rest.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, operands) = match rest.split_once(char::is_whitespace) {
+            Some((m, ops)) => (m, ops.trim()),
+            None => (rest, "")
+        };
+
+        match (section, mnemonic) {
+            (Section::Bss, "resb") | (Section::Bss, "resq") => {
+                let count = operands.parse::<usize>().map_err(|_| fail("invalid size".into()))?;
+                object.bss_len += count * if mnemonic == "resq" { 8 } else { 1 };
+            },
+            (Section::Data, "db") => {
+                for byte in operands.split(',') {
+                    let value = parse_int(byte.trim())
+                        .filter(|v| (-128..=255).contains(v))
+                        .ok_or_else(|| fail(format!("invalid byte `{}`", byte.trim())))?;
+                    object.data.push(value as u8);
+                }
+            },
+            (Section::Text, _) => {
+                let ops = if operands.is_empty() {
+                    Vec::new()
+                } else {
+                    operands.split(',')
+                        .map(|op| parse_operand(op, &scope))
+                        .collect::<Result<Vec<Operand>, String>>()
+                        .map_err(fail)?
+                };
+
+                let inst = encode(mnemonic, &ops).map_err(fail)?;
+
+                let start = object.text.len();
+                let next = start + inst.bytes.len();
+                if let Some((offset, target, relative)) = inst.fixup {
+                    let kind = if relative { FixupKind::Rel32 { next } } else { FixupKind::Abs32 };
+                    object.fixups.push(Fixup { offset: start + offset, target, kind });
+                }
+                object.text.extend_from_slice(&inst.bytes);
+            },
+            _ => return Err(fail(format!("`{}` is not allowed in this section", mnemonic)))
+        }
+    }
+
+    Ok(object)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(src: &str) -> Vec<String> {
+        src.lines().map(String::from).collect()
+    }
+
+    fn text(src: &str) -> Vec<u8> {
+        assemble(&lines(src)).unwrap().text
+    }
+
+    fn error(src: &str) -> String {
+        assemble(&lines(src)).unwrap_err().msg().to_string()
+    }
+
+    #[test]
+    fn rex_prefixes() {
+        assert_eq!(text("mov rax, rbx"), [0x48, 0x89, 0xD8]);
+        assert_eq!(text("mov r8, rax"), [0x49, 0x89, 0xC0]);
+        assert_eq!(text("mov eax, r9d"), [0x44, 0x89, 0xC8]);
+        assert_eq!(text("mov ax, cx"), [0x66, 0x89, 0xC8]);
+        assert_eq!(text("push r12"), [0x41, 0x54]);
+        assert_eq!(text("sete cl"), [0x0F, 0x94, 0xC1]);
+    }
+
+    #[test]
+    fn byte_registers_that_need_rex() {
+        // Without the empty REX prefix these would be ah, ch, dh and bh
+        assert_eq!(text("mov al, sil"), [0x40, 0x88, 0xF0]);
+        assert_eq!(text("mov BYTE [rsp+31], sil"), [0x40, 0x88, 0x74, 0x24, 0x1F]);
+        assert_eq!(text("sete dil"), [0x40, 0x0F, 0x94, 0xC7]);
+        assert_eq!(text("mov al, bl"), [0x88, 0xD8]);
+    }
+
+    #[test]
+    fn sib_bases() {
+        // rsp and r12 as a base need a SIB byte
+        assert_eq!(text("mov rax, [rsp]"), [0x48, 0x8B, 0x04, 0x24]);
+        assert_eq!(text("mov rax, [r12]"), [0x49, 0x8B, 0x04, 0x24]);
+        assert_eq!(text("mov rax, [rsp+8]"), [0x48, 0x8B, 0x44, 0x24, 0x08]);
+        // rbp and r13 as a base need a displacement, even a zero one
+        assert_eq!(text("mov rax, [rbp]"), [0x48, 0x8B, 0x45, 0x00]);
+        assert_eq!(text("mov rax, [r13]"), [0x49, 0x8B, 0x45, 0x00]);
+        assert_eq!(text("mov rax, [r13+rcx*8]"), [0x49, 0x8B, 0x44, 0xCD, 0x00]);
+        assert_eq!(text("lea rax, [rdx+rcx*2]"), [0x48, 0x8D, 0x04, 0x4A]);
+        assert_eq!(text("mov rax, [rbx+512]"), [0x48, 0x8B, 0x83, 0x00, 0x02, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn rip_relative_fixups() {
+        let src = "\
+section .bss
+    counter resq 1
+section .text
+    mov    QWORD [counter], 5
+    mov    rax, [counter]";
+        let mut object = assemble(&lines(src)).unwrap();
+        object.link(0x1000, 0x2000, 0x3000).unwrap();
+
+        // The displacement is relative to the end of the instruction, after
+        // its immediate
+        let disp = (0x3000i32 - (0x1000 + 11)).to_le_bytes();
+        assert_eq!(object.text[..11], [0x48, 0xC7, 0x05, disp[0], disp[1], disp[2], disp[3], 0x05, 0x00, 0x00, 0x00]);
+
+        let disp = (0x3000i32 - (0x1000 + 18)).to_le_bytes();
+        assert_eq!(object.text[11..], [0x48, 0x8B, 0x05, disp[0], disp[1], disp[2], disp[3]]);
+    }
+
+    #[test]
+    fn immediate_sizes() {
+        assert_eq!(text("add rax, 1"), [0x48, 0x83, 0xC0, 0x01]);
+        assert_eq!(text("add rax, -128"), [0x48, 0x83, 0xC0, 0x80]);
+        assert_eq!(text("add rax, 1000"), [0x48, 0x81, 0xC0, 0xE8, 0x03, 0x00, 0x00]);
+        assert_eq!(text("mov rax, -1"), [0x48, 0xC7, 0xC0, 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(text("mov rax, 4294967296"), [0x48, 0xB8, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]);
+        assert_eq!(text("mov eax, 5"), [0xB8, 0x05, 0x00, 0x00, 0x00]);
+        assert_eq!(text("push 5"), [0x6A, 0x05]);
+        assert_eq!(text("push 1000"), [0x68, 0xE8, 0x03, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(error("frob rax").contains("unsupported instruction or operands"));
+        assert!(error("jfoo somewhere").contains("unknown instruction"));
+        assert!(error("mov rax, 1x").contains("invalid operand `1x`"));
+        assert!(error("mov rax, [rcx+rsp*2]").contains("rsp cannot be used as an index register"));
+        assert!(error("add rax, 4294967296").contains("immediate does not fit in 32 bits"));
+        assert!(error("push 4294967296").contains("immediate does not fit in 32 bits"));
+        assert!(error("sete rax").contains("setcc needs a byte operand"));
+        assert!(error("mov rax, 1\nlabel:\nlabel:").contains("line 3"));
+
+        // Symbols are only resolved when linking
+        let mut object = assemble(&lines("jmp nowhere")).unwrap();
+        let err = object.link(0x1000, 0x2000, 0x3000).unwrap_err();
+        assert_eq!(err.msg(), "Undefined symbol `nowhere` in generated assembly.");
+    }
+}
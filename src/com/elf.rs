@@ -0,0 +1,208 @@
+use super::asm::{ Object, Section };
+use crate::Error;
+
+// Where the executable is mapped; matches the default of `ld`
+const BASE_ADDR: u64 = 0x400000;
+const PAGE_SIZE: u64 = 0x1000;
+
+const EHDR_SIZE: u64 = 64;
+const PHDR_SIZE: u64 = 56;
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) / align * align
+}
+
+fn push_u16(out: &mut Vec<u8>, value: u16) { out.extend_from_slice(&value.to_le_bytes()); }
+fn push_u32(out: &mut Vec<u8>, value: u32) { out.extend_from_slice(&value.to_le_bytes()); }
+fn push_u64(out: &mut Vec<u8>, value: u64) { out.extend_from_slice(&value.to_le_bytes()); }
+
+fn push_phdr(out: &mut Vec<u8>, flags: u32, offset: u64, addr: u64, filesz: u64, memsz: u64) {
+    push_u32(out, 1);          // PT_LOAD
+    push_u32(out, flags);
+    push_u64(out, offset);
+    push_u64(out, addr);       // p_vaddr
+    push_u64(out, addr);       // p_paddr
+    push_u64(out, filesz);
+    push_u64(out, memsz);
+    push_u64(out, PAGE_SIZE);  // p_align
+}
+
+// Lays out a statically linked x86_64 ELF executable with two segments:
+//
+//   R+X  ELF header, program headers, .text
+//   RW   .data followed by the zero-filled .bss
+//
+// Section headers are not needed to run the program and are left out.
+pub fn write_executable(mut object: Object, entry: &str) -> Result<Vec<u8>, Error> {
+    let headers_len = EHDR_SIZE + 2 * PHDR_SIZE;
+
+    let text_offset = headers_len;
+    let text_addr = BASE_ADDR + text_offset;
+    let text_end = text_offset + object.text.len() as u64;
+
+    // Keep file offsets and addresses congruent modulo the page size
+    let data_offset = align_up(text_end, PAGE_SIZE);
+    let data_addr = BASE_ADDR + data_offset;
+    let bss_addr = align_up(data_addr + object.data.len() as u64, 16);
+    let rw_memsz = bss_addr + object.bss_len as u64 - data_addr;
+
+    object.link(text_addr, data_addr, bss_addr)?;
+
+    let entry_addr = match object.symbol(entry) {
+        Some((Section::Text, offset)) => text_addr + offset as u64,
+        _ => return Err(Error::without_pos(format!("Entry point `{}` is not defined in .text.", entry)))
+    };
+
+    let mut out: Vec<u8> = Vec::with_capacity(data_offset as usize + object.data.len());
+
+    // e_ident: magic, 64-bit, little endian, version 1, System V ABI
+    out.extend_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0]);
+    out.extend_from_slice(&[0; 8]);
+    push_u16(&mut out, 2);                  // e_type: ET_EXEC
+    push_u16(&mut out, 0x3E);               // e_machine: x86_64
+    push_u32(&mut out, 1);                  // e_version
+    push_u64(&mut out, entry_addr);         // e_entry
+    push_u64(&mut out, EHDR_SIZE);          // e_phoff
+    push_u64(&mut out, 0);                  // e_shoff
+    push_u32(&mut out, 0);                  // e_flags
+    push_u16(&mut out, EHDR_SIZE as u16);   // e_ehsize
+    push_u16(&mut out, PHDR_SIZE as u16);   // e_phentsize
+    push_u16(&mut out, 2);                  // e_phnum
+    push_u16(&mut out, 64);                 // e_shentsize
+    push_u16(&mut out, 0);                  // e_shnum
+    push_u16(&mut out, 0);                  // e_shstrndx
+
+    // PF_R | PF_X, then PF_R | PF_W
+    push_phdr(&mut out, 0b101, 0, BASE_ADDR, text_end, text_end);
+    push_phdr(&mut out, 0b110, data_offset, data_addr, object.data.len() as u64, rw_memsz);
+
+    out.extend_from_slice(&object.text);
+    out.resize(data_offset as usize, 0);
+    out.extend_from_slice(&object.data);
+
+    Ok(out)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn push_shdr(out: &mut Vec<u8>, name: u32, kind: u32, flags: u64, offset: u64, size: u64, link: u32, info: u32, align: u64, entsize: u64) {
+    push_u32(out, name);
+    push_u32(out, kind);
+    push_u64(out, flags);
+    push_u64(out, 0);          // sh_addr
+    push_u64(out, offset);
+    push_u64(out, size);
+    push_u32(out, link);
+    push_u32(out, info);
+    push_u64(out, align);
+    push_u64(out, entsize);
+}
+
+// Appends a NUL-terminated name to a string table, returning its offset
+fn add_string(table: &mut Vec<u8>, name: &str) -> u32 {
+    let offset = table.len() as u32;
+    table.extend_from_slice(name.as_bytes());
+    table.push(0);
+    offset
+}
+
+// Writes a relocatable x86_64 ELF object that `ld` can link on its own. Its
+// sections are:
+//
+//   1 .text  2 .data  3 .bss  4 .rela.text  5 .symtab  6 .strtab  7 .shstrtab
+pub fn write_object(object: &Object) -> Result<Vec<u8>, Error> {
+    let section_index = |section: Section| match section {
+        Section::Text => 1u16,
+        Section::Data => 2,
+        Section::Bss => 3,
+    };
+
+    let mut strtab: Vec<u8> = vec![0];
+    let mut symtab: Vec<u8> = vec![0; 24];
+
+    // STT_SECTION symbols that relocations refer to, symbol indices 1 to 3
+    for section in [Section::Text, Section::Data, Section::Bss] {
+        push_u32(&mut symtab, 0);
+        symtab.push(3);
+        symtab.push(0);
+        push_u16(&mut symtab, section_index(section));
+        push_u64(&mut symtab, 0);
+        push_u64(&mut symtab, 0);
+    }
+
+    // Local symbols must come before global ones
+    let mut symbols = object.symbols();
+    symbols.sort_by_key(|(_, _, _, global)| *global);
+    let first_global = 4 + symbols.iter().filter(|(_, _, _, global)| !global).count();
+
+    for (name, section, offset, global) in symbols {
+        push_u32(&mut symtab, add_string(&mut strtab, name));
+        symtab.push(if global { 0x10 } else { 0 });
+        symtab.push(0);
+        push_u16(&mut symtab, section_index(section));
+        push_u64(&mut symtab, offset as u64);
+        push_u64(&mut symtab, 0);
+    }
+
+    let mut rela: Vec<u8> = Vec::new();
+    for relocation in object.relocations()? {
+        // R_X86_64_PC32 or R_X86_64_32S
+        let kind = if relocation.pc_relative { 2 } else { 11 };
+
+        push_u64(&mut rela, relocation.offset as u64);
+        push_u64(&mut rela, ((section_index(relocation.section) as u64) << 32) | kind);
+        push_u64(&mut rela, relocation.addend as u64);
+    }
+
+    let mut shstrtab: Vec<u8> = vec![0];
+    let names: Vec<u32> = [".text", ".data", ".bss", ".rela.text", ".symtab", ".strtab", ".shstrtab"].iter()
+        .map(|name| add_string(&mut shstrtab, name))
+        .collect();
+
+    // Section contents follow the ELF header, each aligned to 16 bytes
+    let mut out: Vec<u8> = vec![0; EHDR_SIZE as usize];
+    let place = |out: &mut Vec<u8>, bytes: &[u8]| {
+        out.resize(align_up(out.len() as u64, 16) as usize, 0);
+        let offset = out.len() as u64;
+        out.extend_from_slice(bytes);
+        offset
+    };
+
+    let text_offset = place(&mut out, &object.text);
+    let data_offset = place(&mut out, &object.data);
+    let rela_offset = place(&mut out, &rela);
+    let symtab_offset = place(&mut out, &symtab);
+    let strtab_offset = place(&mut out, &strtab);
+    let shstrtab_offset = place(&mut out, &shstrtab);
+    let shoff = place(&mut out, &[]);
+
+    push_shdr(&mut out, 0, 0, 0, 0, 0, 0, 0, 0, 0);
+    // SHF_ALLOC | SHF_EXECINSTR, then SHF_WRITE | SHF_ALLOC
+    push_shdr(&mut out, names[0], 1, 0x6, text_offset, object.text.len() as u64, 0, 0, 16, 0);
+    push_shdr(&mut out, names[1], 1, 0x3, data_offset, object.data.len() as u64, 0, 0, 16, 0);
+    push_shdr(&mut out, names[2], 8, 0x3, data_offset, object.bss_len as u64, 0, 0, 16, 0);
+    // SHF_INFO_LINK
+    push_shdr(&mut out, names[3], 4, 0x40, rela_offset, rela.len() as u64, 5, 1, 8, 24);
+    push_shdr(&mut out, names[4], 2, 0, symtab_offset, symtab.len() as u64, 6, first_global as u32, 8, 24);
+    push_shdr(&mut out, names[5], 3, 0, strtab_offset, strtab.len() as u64, 0, 0, 1, 0);
+    push_shdr(&mut out, names[6], 3, 0, shstrtab_offset, shstrtab.len() as u64, 0, 0, 1, 0);
+
+    let mut header: Vec<u8> = Vec::with_capacity(EHDR_SIZE as usize);
+    header.extend_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0]);
+    header.extend_from_slice(&[0; 8]);
+    push_u16(&mut header, 1);                  // e_type: ET_REL
+    push_u16(&mut header, 0x3E);               // e_machine: x86_64
+    push_u32(&mut header, 1);                  // e_version
+    push_u64(&mut header, 0);                  // e_entry
+    push_u64(&mut header, 0);                  // e_phoff
+    push_u64(&mut header, shoff);              // e_shoff
+    push_u32(&mut header, 0);                  // e_flags
+    push_u16(&mut header, EHDR_SIZE as u16);   // e_ehsize
+    push_u16(&mut header, 0);                  // e_phentsize
+    push_u16(&mut header, 0);                  // e_phnum
+    push_u16(&mut header, 64);                 // e_shentsize
+    push_u16(&mut header, 8);                  // e_shnum
+    push_u16(&mut header, 7);                  // e_shstrndx
+    out[..EHDR_SIZE as usize].copy_from_slice(&header);
+
+    Ok(out)
+}
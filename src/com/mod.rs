@@ -1,55 +1,95 @@
-use std::{fs, u8};
-use std::path::Path;
+use std::fs;
+use std::path::{ Path, PathBuf };
 use std::process::Command;
 
-use super::{ Error, Token, TokenPos, LexerOutput };
+use super::{ Direction, Error, Token, MAX_CALL_DEPTH };
+use super::parse::{ Node, Program };
 
-#[derive(Debug)]
-struct CompilerVars {
-    block_num: usize,
-    block_addrs: Vec<usize>,
-    depth: u8,
-    block_is_dowhile: Vec<bool>,
-    inside_fn: bool
+mod asm;
+mod elf;
+mod runtime;
+
+// Labels the generated code jumps to when the program fails, and what they
+// report on stderr before exiting with 1
+const RUNTIME_ERRORS: &[(&str, &str)] = &[
+    ("div_by_zero", "Division by zero."),
+    ("off_grid", "The pointer left the grid."),
+    ("call_overflow", "Too many nested function calls."),
+];
+
+// What `compile` stops after producing
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Emit {
+    Asm,
+    Obj,
+    #[default]
+    Exe,
+}
+
+impl std::str::FromStr for Emit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "asm" => Ok(Emit::Asm),
+            "obj" => Ok(Emit::Obj),
+            "exe" => Ok(Emit::Exe),
+            _ => Err(Error::without_pos(format!("Unknown output kind: {}", s)))
+        }
+    }
 }
 
-pub fn compile(tokens: &LexerOutput, input_filename: &str) -> Result<(), Error> {
-    // struct deconstruction
-    let LexerOutput { fn_tokens, tokens } = tokens;
+#[derive(Debug, Default, Clone)]
+pub struct CompileOptions {
+    // Assemble and link with nasm and ld instead of the built-in encoder
+    pub use_nasm: bool,
+    // Leave intermediate files (.asm, .o) next to the output
+    pub keep_temps: bool,
+    pub emit: Emit,
+    // Defaults to the input file with the extension of `emit`
+    pub output: Option<PathBuf>
+}
 
+fn generate(program: &Program) -> Vec<String> {
     let mut instructions: Vec<String> = Vec::new();
 
-    let mut compiler_vars = CompilerVars {
-        block_num: 0,
-        block_addrs: Vec::new(),
-        depth: 0,
-        block_is_dowhile: Vec::new(),
-        inside_fn: false
-    };
+    let mut block_num: usize = 0;
 
     instructions.push("section .bss".into());
-    // Allocate memory table (array of 32 pointers)
-    instructions.push("    mem_table resq 32".into());
     instructions.push("    mem_loc   resq 1".into());
-    // Allocate function stack (array of 1024 pointers)
-    instructions.push("    fn_stack resq 1024".into());
+    // Allocate function stack (array of return addresses)
+    instructions.push(format!("    fn_stack resq {}", MAX_CALL_DEPTH));
     instructions.push("    fn_index resq 1".into());
+    // Stack pointer before the program pushed anything
+    instructions.push("    stack_base resq 1".into());
+
+    // String literals
+    instructions.push("section .data".into());
+    for (index, bytes) in program.strings.iter().enumerate() {
+        let bytes: Vec<String> = bytes.iter().map(|b| b.to_string()).collect();
 
-    instructions.push("section .text".into());
-
-    // Import memory functions 
-    instructions.push("extern insert_val".into());
-    instructions.push("extern get_val".into());
-    instructions.push("extern pop_element".into());
-    instructions.push("extern init_table".into());
-    instructions.push("extern free_table".into());
-    instructions.push("extern write_cells".into());
-    
-    // Function for printing (32-bit) numbers
+        if bytes.is_empty() {
+            instructions.push(format!("    str_{}:", index));
+        } else {
+            instructions.push(format!("    str_{}: db {}", index, bytes.join(",")));
+        }
+    }
+    for (label, message) in RUNTIME_ERRORS {
+        let bytes: Vec<String> = format!("runtime error: {}\n", message).bytes().map(|b| b.to_string()).collect();
+        instructions.push(format!("    {}_msg: db {}", label, bytes.join(",")));
+    }
+
+    // Grid memory functions (insert_val, get_val, pop_element, ...)
+    runtime::push_runtime(&mut instructions, program.cell_width);
+
+    // Function for printing numbers
+    // rdi = number, followed by a newline (print) or the byte in rsi (print_sep)
     instructions.push("print:".into());
+    instructions.push("    mov     rsi, 10".into());
+    instructions.push("print_sep:".into());
     instructions.push("    mov     r9, -3689348814741910323".into());
     instructions.push("    sub     rsp, 40".into());
-    instructions.push("    mov     BYTE [rsp+31], 10".into());
+    instructions.push("    mov     BYTE [rsp+31], sil".into());
     instructions.push("    lea     rcx, [rsp+30]".into());
     instructions.push(".L2:".into());
     instructions.push("    mov     rax, rdi".into());
@@ -80,103 +120,372 @@ pub fn compile(tokens: &LexerOutput, input_filename: &str) -> Result<(), Error>
     instructions.push("    add     rsp, 40".into());
     instructions.push("    ret".into());
 
+    // Function for laying out string literals in the grid
+    // rdi = bytes, rsi = length, rdx = distance between cells
+    instructions.push("store_string:".into());
+    instructions.push("    push    rbx".into());
+    instructions.push("    push    r12".into());
+    instructions.push("    push    r13".into());
+    instructions.push("    push    r14".into());
+    instructions.push("    mov     rbx, rdi".into());
+    instructions.push("    mov     r12, [mem_loc]".into());
+    instructions.push("    mov     r13, rsi".into());
+    instructions.push("    mov     r14, rdx".into());
+    instructions.push(".loop:".into());
+    instructions.push("    test    r13, r13".into());
+    instructions.push("    jz      .done".into());
+    instructions.push("    mov     rdi, r12".into());
+    instructions.push("    movzx   rsi, BYTE [rbx]".into());
+    instructions.push("    call    insert_val".into());
+    instructions.push("    inc     rbx".into());
+    instructions.push("    add     r12, r14".into());
+    instructions.push("    dec     r13".into());
+    instructions.push("    jmp     .loop".into());
+    instructions.push(".done:".into());
+    instructions.push("    pop     r14".into());
+    instructions.push("    pop     r13".into());
+    instructions.push("    pop     r12".into());
+    instructions.push("    pop     rbx".into());
+    instructions.push("    ret".into());
+
+    // Function for writing cells as raw bytes
+    // rdi = number of cells to write, starting at mem_loc
+    instructions.push("puts_cells:".into());
+    instructions.push("    push    rbx".into());
+    instructions.push("    push    r12".into());
+    instructions.push("    push    r13".into());
+    instructions.push("    mov     rbx, rdi".into());
+    instructions.push("    mov     r12, [mem_loc]".into());
+    instructions.push(".loop:".into());
+    instructions.push("    test    rbx, rbx".into());
+    instructions.push("    jz      .done".into());
+    instructions.push("    mov     rdi, r12".into());
+    instructions.push("    call    get_val".into());
+    instructions.push("    push    rax".into());
+    instructions.push("    mov     rax, 1".into());
+    instructions.push("    mov     rdi, 1".into());
+    instructions.push("    mov     rsi, rsp".into());
+    instructions.push("    mov     rdx, 1".into());
+    instructions.push("    syscall".into());
+    instructions.push("    pop     rax".into());
+    instructions.push("    inc     r12".into());
+    instructions.push("    dec     rbx".into());
+    instructions.push("    jmp     .loop".into());
+    instructions.push(".done:".into());
+    instructions.push("    pop     r13".into());
+    instructions.push("    pop     r12".into());
+    instructions.push("    pop     rbx".into());
+    instructions.push("    ret".into());
+
+    // Function for reading a line of stdin into the cells right of mem_loc
+    // Returns the number of bytes stored (excluding the newline) in rax
+    instructions.push("read_line:".into());
+    instructions.push("    push    rbx".into());
+    instructions.push("    push    r12".into());
+    instructions.push("    push    r13".into());
+    instructions.push("    xor     rbx, rbx".into());
+    instructions.push("    mov     r12, [mem_loc]".into());
+    instructions.push(".loop:".into());
+    instructions.push("    push    0".into());
+    instructions.push("    xor     rax, rax".into());
+    instructions.push("    xor     rdi, rdi".into());
+    instructions.push("    mov     rsi, rsp".into());
+    instructions.push("    mov     rdx, 1".into());
+    instructions.push("    syscall".into());
+    instructions.push("    pop     r13".into());
+    instructions.push("    cmp     rax, 1".into());
+    instructions.push("    jne     .done".into());
+    instructions.push("    cmp     r13, 10".into());
+    instructions.push("    je      .done".into());
+    instructions.push("    lea     rdi, [r12+rbx]".into());
+    instructions.push("    mov     rsi, r13".into());
+    instructions.push("    call    insert_val".into());
+    instructions.push("    inc     rbx".into());
+    instructions.push("    jmp     .loop".into());
+    instructions.push(".done:".into());
+    instructions.push("    mov     rax, rbx".into());
+    instructions.push("    pop     r13".into());
+    instructions.push("    pop     r12".into());
+    instructions.push("    pop     rbx".into());
+    instructions.push("    ret".into());
+
+    // Runtime errors: rsi = message, rdx = length
+    for (label, message) in RUNTIME_ERRORS {
+        instructions.push(format!("{}:", label));
+        instructions.push(format!("    mov     rsi, {}_msg", label));
+        instructions.push(format!("    mov     rdx, {}", format!("runtime error: {}\n", message).len()));
+        instructions.push("    jmp     runtime_error".into());
+    }
+    instructions.push("runtime_error:".into());
+    instructions.push("    mov     rax, 1".into());
+    instructions.push("    mov     rdi, 2".into());
+    instructions.push("    syscall".into());
+    instructions.push("    mov     rax, 60".into());
+    instructions.push("    mov     rdi, 1".into());
+    instructions.push("    syscall".into());
+
     // Write function instructions
-    for (token, _) in fn_tokens {
-        // last instruction in function
-        if let Token::End(-1) = token {
-            // push fn_stack[fn_index] to stack
-            instructions.push("    mov     rcx, QWORD fn_stack".into());
-            instructions.push("    mov     rdx, QWORD [fn_index]".into());
-            instructions.push("    mov     rax, QWORD [rcx+rdx*8]".into());
-            instructions.push("    push    rax".into());
-            // decrement fn_index
-            instructions.push("    dec     QWORD [fn_index]".into());
-        }
+    for (index, function) in program.functions.iter().enumerate() {
+        instructions.push(format!("; -- fn {} --", function.name));
+        instructions.push(format!("fn_{}:", index));
+        // pop return address into fn_stack[fn_index], unless it is full
+        instructions.push("    mov     rcx, QWORD fn_stack".into());
+        instructions.push("    mov     rdx, QWORD [fn_index]".into());
+        instructions.push(format!("    cmp     rdx, {}", MAX_CALL_DEPTH));
+        instructions.push("    jae     call_overflow".into());
+        instructions.push("    pop     rax".into());
+        instructions.push("    mov     QWORD [rcx+rdx*8], rax".into());
+        // increment fn_index
+        instructions.push("    inc     QWORD [fn_index]".into());
 
-        push_instructions_from_token(token, &mut instructions, &mut compiler_vars);
-
-        // first instruction in function
-        if let Token::Fn(..) = token {
-            // increment fn_index
-            instructions.push("    inc     QWORD [fn_index]".into());
-            // pop top element of stack into fn_stack[fn_index] 
-            instructions.push("    mov     rcx, QWORD fn_stack".into());
-            instructions.push("    mov     rdx, QWORD [fn_index]".into());
-            instructions.push("    pop     rax".into());
-            instructions.push("    mov     QWORD [rcx+rdx*8], rax".into());
-        }
+        push_instructions_from_nodes(&function.body, &program.strings, &mut instructions, &mut block_num);
+
+        instructions.push("; -- end --".into());
+        // decrement fn_index
+        instructions.push("    dec     QWORD [fn_index]".into());
+        // push fn_stack[fn_index] to stack
+        instructions.push("    mov     rcx, QWORD fn_stack".into());
+        instructions.push("    mov     rdx, QWORD [fn_index]".into());
+        instructions.push("    mov     rax, QWORD [rcx+rdx*8]".into());
+        instructions.push("    push    rax".into());
+        instructions.push("    ret".into());
     }
 
     // Executable entry point
     instructions.push("global _start".into());
     instructions.push("_start:".into());
 
-    // Initialize mem_table
-    instructions.push("    mov    rdi, mem_table".into());
-    instructions.push("    call   init_table".into());
+    // Initialize the grid
+    instructions.push("    call   grid_init".into());
     // Initialize fn_stack
     instructions.push("    mov    QWORD [fn_index], 0".into());
+    instructions.push("    mov    [stack_base], rsp".into());
 
     // Write main function instructions
-    for (token, _) in tokens {
-        push_instructions_from_token(token, &mut instructions, &mut compiler_vars);
-    }
+    push_instructions_from_nodes(&program.body, &program.strings, &mut instructions, &mut block_num);
 
     instructions.push("; -- exit --".into());
-    instructions.push("    mov    rdi, mem_table".into());
-    instructions.push("    call   free_table".into());
+    // Return code = top element on stack, or 0 if it is empty
+    instructions.push("    xor    rdi, rdi".into());
+    instructions.push("    cmp    rsp, [stack_base]".into());
+    instructions.push("    je     .exit".into());
+    instructions.push("    pop    rdi".into());
+    instructions.push(".exit:".into());
     instructions.push("    mov    rax, 60".into());
-    instructions.push("    pop    rdi".into()); // return code = top element on stack
     instructions.push("    syscall".into());
 
-    let file_contents: String = instructions.join("\n");
- 
-    let output_base = Path::new(input_filename);
-
-    fs::write(output_base.with_extension("asm"), &file_contents).map_err(
-        |err| Error { msg: err.to_string(), pos: TokenPos::default() }
-    ).expect("Failed to write to file.");
-
-    // Compile asm
-    Command::new("nasm")
-        .args([
-              "-felf64", 
-              output_base.with_extension("asm").to_str().unwrap()
-        ]).output().expect("Failed to compile assembly.");
-
-    // Compile c lib(s)
-    Command::new("gcc")
-        .args([
-              "-c",
-              "-g",
-              "-o",
-              "mem.o",
-              "./src/com/libs/mem.c",
-              "-static",
-              "-static-libgcc",
-        ]).output().expect("Failed to compile c libs.");
-
-    // Link libs to asm
-    Command::new("ld")
-        .args([
-              "-dynamic-linker", 
-              "/lib64/ld-linux-x86-64.so.2",
-              "-o",
-              output_base.with_extension("").to_str().unwrap(),
-              "-lc",
-              output_base.with_extension("o").to_str().unwrap(),
-              "mem.o"
-        ]).output().expect("Failed to link.");
+    instructions
+}
+
+// Compiles the program into the artifact requested by `options.emit`,
+// returning its path
+pub fn compile(program: &Program, input_filename: &str, options: &CompileOptions) -> Result<PathBuf, Error> {
+    let instructions = generate(program);
+
+    let output = match &options.output {
+        Some(path) => path.clone(),
+        None => Path::new(input_filename).with_extension(match options.emit {
+            Emit::Asm => "asm",
+            Emit::Obj => "o",
+            Emit::Exe => ""
+        })
+    };
+
+    if let Some(dir) = output.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)
+            .map_err(|err| Error::without_pos(format!("Failed to create {}: {}", dir.display(), err)))?;
+    }
+
+    if options.emit == Emit::Asm {
+        write_output(&output, instructions.join("\n").as_bytes(), false)?;
+        return Ok(output);
+    }
+
+    if options.use_nasm {
+        return link_with_nasm(&instructions, &output, options);
+    }
+
+    if options.keep_temps {
+        write_output(&temp_path(&output, "asm"), instructions.join("\n").as_bytes(), false)?;
+    }
+
+    let object = asm::assemble(&instructions)?;
+
+    if options.emit == Emit::Obj {
+        write_output(&output, &elf::write_object(&object)?, false)?;
+    } else {
+        write_output(&output, &elf::write_executable(object, "_start")?, true)?;
+    }
+
+    Ok(output)
+}
+
+// Intermediate files are named after the output, e.g. `build/foo.asm` for
+// `build/foo`
+fn temp_path(output: &Path, extension: &str) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+fn write_output(path: &Path, bytes: &[u8], executable: bool) -> Result<(), Error> {
+    fs::write(path, bytes)
+        .map_err(|err| Error::without_pos(format!("Failed to write {}: {}", path.display(), err)))?;
+
+    #[cfg(unix)]
+    if executable {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+            .map_err(|err| Error::without_pos(format!("Failed to make {} executable: {}", path.display(), err)))?;
+    }
 
     Ok(())
 }
 
-fn push_instructions_from_token(token: &Token, instructions: &mut Vec<String>, compiler_vars: &mut CompilerVars) {
+fn link_with_nasm(instructions: &[String], output: &Path, options: &CompileOptions) -> Result<PathBuf, Error> {
+    let asm_path = temp_path(output, "asm");
+    let obj_path = if options.emit == Emit::Obj { output.to_path_buf() } else { temp_path(output, "o") };
+
+    write_output(&asm_path, instructions.join("\n").as_bytes(), false)?;
+
+    let mut result = run_tool("assemble", Command::new("nasm").arg("-felf64").arg("-o").arg(&obj_path).arg(&asm_path));
+
+    if options.emit == Emit::Exe {
+        // Link into a static executable; the runtime is part of the assembly
+        result = result.and_then(|_| run_tool("link", Command::new("ld").arg("-o").arg(output).arg(&obj_path)));
+    }
+
+    if !options.keep_temps {
+        let _ = fs::remove_file(&asm_path);
+        if obj_path != output {
+            let _ = fs::remove_file(&obj_path);
+        }
+    }
+
+    result.map(|_| output.to_path_buf())
+}
+
+// Runs a compiled program with the given arguments, its stdin, stdout and
+// stderr connected to ours. Returns its exit status, or 128 + the signal
+// number if it was killed, like a shell would.
+pub fn run_executable(path: &Path, args: &[&str]) -> Result<i32, Error> {
+    // A bare file name would be looked up in PATH instead
+    let path = if path.is_relative() { Path::new(".").join(path) } else { path.to_path_buf() };
+
+    let status = Command::new(&path).args(args).status().map_err(|err| {
+        Error::tool(format!("Failed to run `{}`: {}", path.display(), err), "run", path.display().to_string(), None, String::new())
+    })?;
+
+    if let Some(code) = status.code() {
+        return Ok(code);
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+
+        if let Some(signal) = status.signal() {
+            return Ok(128 + signal);
+        }
+    }
+
+    Ok(1)
+}
+
+// Runs an external program, turning a failure to start it or a non-zero exit
+// status into an `Error` that names the stage and includes its stderr
+fn run_tool(stage: &str, command: &mut Command) -> Result<(), Error> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let command_line = std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let output = command.output().map_err(|err| {
+        Error::tool(format!("Failed to {}: could not run `{}`: {}", stage, program, err), stage, command_line.clone(), None, String::new())
+    })?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let msg = match output.status.code() {
+        Some(code) => format!("Failed to {}: `{}` exited with status {}.", stage, program, code),
+        None => format!("Failed to {}: `{}` was terminated by a signal.", stage, program)
+    };
+
+    Err(Error::tool(msg, stage, command_line, output.status.code(), String::from_utf8_lossy(&output.stderr).into_owned()))
+}
+
+fn push_instructions_from_nodes(nodes: &[Node], strings: &[Vec<u8>], instructions: &mut Vec<String>, block_num: &mut usize) {
+    for node in nodes {
+        match node {
+            Node::Word(token, _) => push_instructions_from_token(token, strings, instructions),
+            Node::If(block) => {
+                let else_addr = *block_num;
+                let end_addr = *block_num + 1;
+                *block_num += 2;
+
+                instructions.push("; -- if --".into());
+                instructions.push("    pop    rax".into());
+                instructions.push("    cmp    rax, 0".into());
+                instructions.push(format!("    je     addr_{}", else_addr));
+
+                push_instructions_from_nodes(&block.then_body, strings, instructions, block_num);
+
+                if let Some((_, else_body)) = &block.else_body {
+                    instructions.push("; -- else --".into());
+                    instructions.push(format!("    jmp    addr_{}", end_addr));
+                    instructions.push(format!("addr_{}:", else_addr));
+
+                    push_instructions_from_nodes(else_body, strings, instructions, block_num);
+                } else {
+                    instructions.push(format!("addr_{}:", else_addr));
+                }
+
+                instructions.push("; -- end --".into());
+                instructions.push(format!("addr_{}:", end_addr));
+            },
+            Node::While(block) => {
+                let start_addr = *block_num;
+                let end_addr = *block_num + 1;
+                *block_num += 2;
+
+                instructions.push("; -- while --".into());
+                instructions.push(format!("addr_{}:", start_addr));
+
+                push_instructions_from_nodes(&block.cond, strings, instructions, block_num);
+
+                instructions.push("; -- do --".into());
+                instructions.push("    pop    rax".into());
+                instructions.push("    cmp    rax, 0".into());
+                instructions.push(format!("    je     addr_{}", end_addr));
+
+                push_instructions_from_nodes(&block.body, strings, instructions, block_num);
+
+                instructions.push("; -- end --".into());
+                instructions.push(format!("    jmp    addr_{}", start_addr));
+                instructions.push(format!("addr_{}:", end_addr));
+            }
+        }
+    }
+}
+
+fn push_instructions_from_token(token: &Token, strings: &[Vec<u8>], instructions: &mut Vec<String>) {
     instructions.push(token.to_asm_comment());
 
     match token {
         Token::Num(num) => {
-            instructions.push(format!("    push {}", num));
+            // push only takes sign-extended 32-bit immediates
+            if *num <= i32::MAX as u64 {
+                instructions.push(format!("    push   {}", num));
+            } else {
+                instructions.push(format!("    mov    rax, {}", num));
+                instructions.push("    push   rax".into());
+            }
         },
         Token::OpAdd => {
             instructions.push("    pop    rax".into());
@@ -201,6 +510,8 @@ fn push_instructions_from_token(token: &Token, instructions: &mut Vec<String>, c
             instructions.push("    pop    rax".into());
             // div performs division against the 64 bit number rdx:rax,
             // so rdx needs to be zero'd before the division
+            instructions.push("    test   rcx, rcx".into());
+            instructions.push("    jz     div_by_zero".into());
             instructions.push("    xor    rdx, rdx".into()); 
             instructions.push("    div    rcx".into()); 
             instructions.push("    push   rax".into());
@@ -210,11 +521,41 @@ fn push_instructions_from_token(token: &Token, instructions: &mut Vec<String>, c
             instructions.push("    call   print".into());
         },
         Token::Write => {
-            instructions.push("    mov    rdi, mem_table".into());
-            instructions.push("    mov    rsi, [mem_loc]".into());
-            instructions.push("    pop    rdx".into());
+            instructions.push("    mov    rdi, [mem_loc]".into());
+            instructions.push("    pop    rsi".into());
             instructions.push("    call   write_cells".into());
         },
+        Token::Emit => {
+            // Write the low byte of the top of the stack straight from the stack
+            instructions.push("    mov    rax, 1".into());
+            instructions.push("    mov    rdi, 1".into());
+            instructions.push("    mov    rsi, rsp".into());
+            instructions.push("    mov    rdx, 1".into());
+            instructions.push("    syscall".into());
+            instructions.push("    pop    rax".into());
+        },
+        Token::Puts => {
+            instructions.push("    pop    rdi".into());
+            instructions.push("    call   puts_cells".into());
+        },
+        Token::Key => {
+            // Read into a zeroed slot on the stack, pushing -1 at end of input
+            instructions.push("    push   0".into());
+            instructions.push("    xor    rax, rax".into());
+            instructions.push("    xor    rdi, rdi".into());
+            instructions.push("    mov    rsi, rsp".into());
+            instructions.push("    mov    rdx, 1".into());
+            instructions.push("    syscall".into());
+            instructions.push("    pop    rcx".into());
+            instructions.push("    mov    rdx, -1".into());
+            instructions.push("    cmp    rax, 1".into());
+            instructions.push("    cmovne rcx, rdx".into());
+            instructions.push("    push   rcx".into());
+        },
+        Token::ReadLine => {
+            instructions.push("    call   read_line".into());
+            instructions.push("    push   rax".into());
+        },
         Token::Dup => {
             instructions.push("    pop    rax".into());
             instructions.push("    push   rax".into());
@@ -236,51 +577,7 @@ fn push_instructions_from_token(token: &Token, instructions: &mut Vec<String>, c
             instructions.push("    push   rax".into());
             instructions.push("    push   rcx".into());
         },
-        Token::If(_) => {
-            instructions.push("    pop    rax".into());
-            instructions.push("    cmp    rax, 0".into());
-            instructions.push(format!("    je     addr_{}", compiler_vars.block_num));
-            compiler_vars.block_addrs.push(compiler_vars.block_num);
-            compiler_vars.block_num += 1;
-            compiler_vars.depth += 1;
-            compiler_vars.block_is_dowhile.push(false);
-        },
-        Token::Else(_) => {
-            let block_addr = compiler_vars.block_addrs.pop().unwrap();
-            instructions.push(format!("    jmp    addr_{}", compiler_vars.block_num));
-            compiler_vars.block_addrs.push(compiler_vars.block_num);
-            compiler_vars.block_num += 1;
-            instructions.push(format!("addr_{}:", block_addr));
-        },
-        Token::While => {
-            instructions.push(format!("addr_{}:", compiler_vars.block_num));
-            compiler_vars.block_num += 1;
-            compiler_vars.block_addrs.push(compiler_vars.block_num);
-            compiler_vars.block_addrs.push(compiler_vars.block_num - 1);
-            compiler_vars.block_num += 1;
-            compiler_vars.depth += 1;
-        },
-        Token::Do(_) => {
-            instructions.push("    pop    rax".into());
-            instructions.push("    cmp    rax, 0".into());
-            instructions.push(format!("    je     addr_{}", compiler_vars.block_addrs[compiler_vars.block_addrs.len() - 2]));
-            compiler_vars.block_is_dowhile.push(true);
-        },
-        Token::End(ip) => {
-            if *ip == -1isize {
-                instructions.push("    ret".into());
-                compiler_vars.inside_fn = false;
-            } else {
-                if compiler_vars.block_is_dowhile.pop().is_some() {
-                    instructions.push(format!("    jmp    addr_{}", compiler_vars.block_addrs.pop().unwrap()));
-                }
-
-                instructions.push(format!("addr_{}:", compiler_vars.block_addrs.pop().unwrap()));
-            }
-
-            compiler_vars.depth -= 1;
-        },
-        Token::Eq | Token::And => {
+        Token::Eq => {
             instructions.push("    pop    rcx".into());
             instructions.push("    pop    rdx".into());
             instructions.push("    cmp    rdx, rcx".into());
@@ -295,7 +592,7 @@ fn push_instructions_from_token(token: &Token, instructions: &mut Vec<String>, c
             instructions.push("    cmp    rdx, rcx".into());
             instructions.push("    mov    rax, 0".into());
             instructions.push("    mov    rdx, 1".into());
-            instructions.push("    cmovg  rax, rdx".into());
+            instructions.push("    cmova  rax, rdx".into());
             instructions.push("    push   rax".into());
         },
         Token::LT => {
@@ -304,16 +601,26 @@ fn push_instructions_from_token(token: &Token, instructions: &mut Vec<String>, c
             instructions.push("    cmp    rdx, rcx".into());
             instructions.push("    mov    rax, 0".into());
             instructions.push("    mov    rdx, 1".into());
-            instructions.push("    cmovl  rax, rdx".into());
+            instructions.push("    cmovb  rax, rdx".into());
             instructions.push("    push   rax".into());
         },
-        Token::Not => {
+        Token::And => {
             instructions.push("    pop    rax".into());
+            instructions.push("    pop    rcx".into());
+            instructions.push("    mov    rdx, 1".into());
             instructions.push("    cmp    rax, 0".into());
-            instructions.push("    mov    rcx, 1".into());
+            instructions.push("    cmovne rax, rdx".into());
+            instructions.push("    cmp    rcx, 0".into());
             instructions.push("    cmove  rax, rcx".into());
             instructions.push("    push   rax".into());
         },
+        Token::Not => {
+            instructions.push("    pop    rax".into());
+            instructions.push("    xor    ecx, ecx".into());
+            instructions.push("    test   rax, rax".into());
+            instructions.push("    sete   cl".into());
+            instructions.push("    push   rcx".into());
+        },
         Token::Or => {
             instructions.push("    pop    rax".into());
             instructions.push("    pop    rcx".into());
@@ -326,22 +633,24 @@ fn push_instructions_from_token(token: &Token, instructions: &mut Vec<String>, c
         },
         Token::Up => {
             instructions.push("    pop    rax".into());
-            instructions.push("    mov    rcx, 4294967296".into());
-            instructions.push("    mul    rcx".into());
+            push_grid_check(Direction::Up, instructions);
+            instructions.push("    shl    rax, 32".into());
             instructions.push("    sub    [mem_loc], rax".into());
         },
         Token::Down => {
             instructions.push("    pop    rax".into());
-            instructions.push("    mov    rcx, 4294967296".into());
-            instructions.push("    mul    rcx".into());
+            push_grid_check(Direction::Down, instructions);
+            instructions.push("    shl    rax, 32".into());
             instructions.push("    add    [mem_loc], rax".into());
         },
         Token::Left => {
             instructions.push("    pop    rax".into());
+            push_grid_check(Direction::Left, instructions);
             instructions.push("    sub    [mem_loc], rax".into());
         },
         Token::Right => {
             instructions.push("    pop    rax".into());
+            push_grid_check(Direction::Right, instructions);
             instructions.push("    add    [mem_loc], rax".into());
         },
         Token::Loc => {
@@ -349,38 +658,67 @@ fn push_instructions_from_token(token: &Token, instructions: &mut Vec<String>, c
             instructions.push("    push   rax".into());
         },
         Token::Store => {
-            instructions.push("    mov    rdi, mem_table".into());
-            instructions.push("    mov    rsi, [mem_loc]".into());
-            instructions.push("    pop    rdx".into());
+            instructions.push("    mov    rdi, [mem_loc]".into());
+            instructions.push("    pop    rsi".into());
             instructions.push("    call   insert_val".into());
         },
         Token::Load => {
-            instructions.push("    mov    rdi, mem_table".into());
-            instructions.push("    mov    rsi, [mem_loc]".into());
-            instructions.push("    xor    rax, rax".into());
+            instructions.push("    mov    rdi, [mem_loc]".into());
             instructions.push("    call   pop_element".into());
             instructions.push("    push   rax".into());
         },
         Token::Copy => {
-            instructions.push("    mov    rdi, mem_table".into());
-            instructions.push("    mov    rsi, [mem_loc]".into());
-            instructions.push("    xor    rax, rax".into());
+            instructions.push("    mov    rdi, [mem_loc]".into());
             instructions.push("    call   get_val".into());
             instructions.push("    push   rax".into());
         },
-        Token::Fn(name, _) => {
-            let name = String::from_utf8_lossy(name);
-            let (name, _) = name.split_once('\0').unwrap();
+        Token::Str(index, direction) => {
+            let stride: i64 = match direction {
+                Direction::Up => -4294967296,
+                Direction::Down => 4294967296,
+                Direction::Left => -1,
+                Direction::Right => 1,
+            };
+            let length = strings[*index].len();
 
-            instructions.push(format!("{}: ", name));
-            compiler_vars.depth += 1;
-            compiler_vars.inside_fn = true;
+            // The last byte is the farthest from the pointer
+            if length > 0 {
+                instructions.push(format!("    mov    rax, {}", length - 1));
+                push_grid_check(*direction, instructions);
+            }
+
+            instructions.push(format!("    mov    rdi, str_{}", index));
+            instructions.push(format!("    mov    rsi, {}", length));
+            instructions.push(format!("    mov    rdx, {}", stride));
+            instructions.push("    call   store_string".into());
+            instructions.push(format!("    push   {}", length));
+        },
+        Token::FnCall(index) => {
+            instructions.push(format!("    call   fn_{}", index));
         }
-        Token::FnCall(name) => {
-            let name = String::from_utf8_lossy(name);
-            let (name, _) = name.split_once('\0').unwrap();
-            
-            instructions.push(format!("    call {}", name));
+    }
+}
+
+// Jumps to `off_grid` unless the pointer can move rax cells in `direction`.
+// Clobbers rdx
+fn push_grid_check(direction: Direction, instructions: &mut Vec<String>) {
+    instructions.push("    mov    rdx, [mem_loc]".into());
+    match direction {
+        Direction::Up | Direction::Down => instructions.push("    shr    rdx, 32".into()),
+        Direction::Left | Direction::Right => instructions.push("    mov    edx, edx".into()),
+    }
+
+    match direction {
+        Direction::Up | Direction::Left => {
+            instructions.push("    cmp    rdx, rax".into());
+            instructions.push("    jb     off_grid".into());
+        },
+        Direction::Down | Direction::Right => {
+            // The new coordinate must still fit in 32 bits
+            instructions.push("    add    rdx, rax".into());
+            instructions.push("    jc     off_grid".into());
+            instructions.push("    shr    rdx, 32".into());
+            instructions.push("    jnz    off_grid".into());
         }
     }
 }
@@ -0,0 +1,239 @@
+// Grid runtime emitted into every compiled program, so executables need
+// neither a C compiler nor libc and work from any directory.
+//
+// The grid is split into tiles of 64x64 cells, each mmap'd (and so zeroed) the
+// first time one of its cells is touched. Tiles are found through an
+// open-addressing hash table keyed by the address of the tile's first cell,
+// which doubles in size when half full, and the last tile used is cached.
+// Cells that were never stored read as 0, like in the simulator. Each cell is
+// as wide as the program's cell width, so values are truncated on store.
+
+use crate::CellWidth;
+
+const TILE_CELLS: usize = 64 * 64;
+const DIR_SLOTS: usize = 1024;
+
+pub fn push_runtime(instructions: &mut Vec<String>, width: CellWidth) {
+    let bytes = width.bytes();
+    // Instructions for reading the cell at `[rax]`/`[rcx]` into rax, clearing
+    // it, and storing rsi into it
+    let (load, size, value) = match width {
+        CellWidth::U8 => ("movzx   eax, BYTE", "BYTE", "sil"),
+        CellWidth::U16 => ("movzx   eax, WORD", "WORD", "si"),
+        CellWidth::U32 => ("mov     eax, DWORD", "DWORD", "esi"),
+        CellWidth::U64 => ("mov     rax, QWORD", "QWORD", "rsi"),
+    };
+
+    instructions.push("section .bss".into());
+    // Tile directory: `[key, tile]` slots, an empty slot has no tile
+    instructions.push("    dir_ptr   resq 1".into());
+    instructions.push("    dir_mask  resq 1".into());
+    instructions.push("    dir_count resq 1".into());
+    instructions.push("    last_key  resq 1".into());
+    instructions.push("    last_tile resq 1".into());
+
+    instructions.push("section .data".into());
+    // "out of memory\n"
+    instructions.push("    oom_msg: db 111,117,116,32,111,102,32,109,101,109,111,114,121,10".into());
+
+    instructions.push("section .text".into());
+
+    // rdi = number of bytes
+    // Returns zeroed memory in rax, exiting if there is none left
+    instructions.push("map_pages:".into());
+    instructions.push("    mov     rsi, rdi".into());
+    instructions.push("    xor     rdi, rdi".into());
+    instructions.push("    mov     rdx, 3".into());   // PROT_READ | PROT_WRITE
+    instructions.push("    mov     r10, 34".into());  // MAP_PRIVATE | MAP_ANONYMOUS
+    instructions.push("    mov     r8, -1".into());
+    instructions.push("    xor     r9, r9".into());
+    instructions.push("    mov     rax, 9".into());
+    instructions.push("    syscall".into());
+    instructions.push("    cmp     rax, -4096".into());
+    instructions.push("    ja      .oom".into());
+    instructions.push("    ret".into());
+    instructions.push(".oom:".into());
+    instructions.push("    mov     rax, 1".into());
+    instructions.push("    mov     rdi, 2".into());
+    instructions.push("    mov     rsi, oom_msg".into());
+    instructions.push("    mov     rdx, 14".into());
+    instructions.push("    syscall".into());
+    instructions.push("    mov     rax, 60".into());
+    instructions.push("    mov     rdi, 1".into());
+    instructions.push("    syscall".into());
+
+    instructions.push("grid_init:".into());
+    instructions.push(format!("    mov     rdi, {}", DIR_SLOTS * 16));
+    instructions.push("    call    map_pages".into());
+    instructions.push("    mov     [dir_ptr], rax".into());
+    instructions.push(format!("    mov     QWORD [dir_mask], {}", DIR_SLOTS - 1));
+    instructions.push("    ret".into());
+
+    // r8 = tile key
+    // Returns the directory slot holding the key, or the empty slot where it
+    // belongs, in rax
+    instructions.push("dir_slot:".into());
+    instructions.push("    mov     rax, r8".into());
+    instructions.push("    mov     rcx, 0x9E3779B97F4A7C15".into());
+    instructions.push("    mul     rcx".into());
+    instructions.push("    mov     rax, rdx".into());
+    instructions.push("    mov     r9, [dir_ptr]".into());
+    instructions.push(".probe:".into());
+    instructions.push("    and     rax, [dir_mask]".into());
+    instructions.push("    mov     rcx, rax".into());
+    instructions.push("    shl     rcx, 4".into());
+    instructions.push("    add     rcx, r9".into());
+    instructions.push("    cmp     QWORD [rcx+8], 0".into());
+    instructions.push("    je      .done".into());
+    instructions.push("    cmp     [rcx], r8".into());
+    instructions.push("    je      .done".into());
+    instructions.push("    inc     rax".into());
+    instructions.push("    jmp     .probe".into());
+    instructions.push(".done:".into());
+    instructions.push("    mov     rax, rcx".into());
+    instructions.push("    ret".into());
+
+    // Moves every tile into a directory twice the size
+    instructions.push("grow_dir:".into());
+    instructions.push("    push    rbx".into());
+    instructions.push("    push    r12".into());
+    instructions.push("    push    r13".into());
+    instructions.push("    mov     r12, [dir_ptr]".into());
+    instructions.push("    mov     r13, [dir_mask]".into());
+    instructions.push("    inc     r13".into());
+    instructions.push("    mov     rdi, r13".into());
+    instructions.push("    shl     rdi, 5".into());
+    instructions.push("    call    map_pages".into());
+    instructions.push("    mov     [dir_ptr], rax".into());
+    instructions.push("    lea     rcx, [r13+r13-1]".into());
+    instructions.push("    mov     [dir_mask], rcx".into());
+    instructions.push("    xor     rbx, rbx".into());
+    instructions.push(".loop:".into());
+    instructions.push("    cmp     rbx, r13".into());
+    instructions.push("    je      .done".into());
+    instructions.push("    mov     rdi, rbx".into());
+    instructions.push("    shl     rdi, 4".into());
+    instructions.push("    mov     r8, [r12+rdi]".into());
+    instructions.push("    mov     rsi, [r12+rdi+8]".into());
+    instructions.push("    test    rsi, rsi".into());
+    instructions.push("    jz      .next".into());
+    instructions.push("    call    dir_slot".into());
+    instructions.push("    mov     [rax], r8".into());
+    instructions.push("    mov     [rax+8], rsi".into());
+    instructions.push(".next:".into());
+    instructions.push("    inc     rbx".into());
+    instructions.push("    jmp     .loop".into());
+    instructions.push(".done:".into());
+    instructions.push("    mov     rax, 11".into());
+    instructions.push("    mov     rdi, r12".into());
+    instructions.push("    mov     rsi, r13".into());
+    instructions.push("    shl     rsi, 4".into());
+    instructions.push("    syscall".into());
+    instructions.push("    pop     r13".into());
+    instructions.push("    pop     r12".into());
+    instructions.push("    pop     rbx".into());
+    instructions.push("    ret".into());
+
+    // rdi = loc
+    // Returns a pointer to the cell in rax, mapping its tile on first touch
+    instructions.push("cell_ptr:".into());
+    instructions.push("    mov     rax, 0xFFFFFFC0FFFFFFC0".into()); // clears the low 6 bits of x and y
+    instructions.push("    and     rax, rdi".into());
+    instructions.push("    mov     rdx, [last_tile]".into());
+    instructions.push("    test    rdx, rdx".into());
+    instructions.push("    jz      .lookup".into());
+    instructions.push("    cmp     rax, [last_key]".into());
+    instructions.push("    je      .found".into());
+    instructions.push(".lookup:".into());
+    instructions.push("    push    rdi".into());
+    instructions.push("    mov     r8, rax".into());
+    instructions.push("    call    dir_slot".into());
+    instructions.push("    mov     rdx, [rax+8]".into());
+    instructions.push("    test    rdx, rdx".into());
+    instructions.push("    jnz     .cache".into());
+    instructions.push("    mov     [rax], r8".into());
+    instructions.push("    push    rax".into());
+    instructions.push("    push    r8".into());
+    instructions.push(format!("    mov     rdi, {}", TILE_CELLS * bytes as usize));
+    instructions.push("    call    map_pages".into());
+    instructions.push("    mov     rdx, rax".into());
+    instructions.push("    pop     r8".into());
+    instructions.push("    pop     rax".into());
+    instructions.push("    mov     [rax+8], rdx".into());
+    instructions.push("    inc     QWORD [dir_count]".into());
+    instructions.push("    mov     rax, [dir_count]".into());
+    instructions.push("    add     rax, rax".into());
+    instructions.push("    cmp     rax, [dir_mask]".into());
+    instructions.push("    jb      .cache".into());
+    instructions.push("    push    rdx".into());
+    instructions.push("    push    r8".into());
+    instructions.push("    call    grow_dir".into());
+    instructions.push("    pop     r8".into());
+    instructions.push("    pop     rdx".into());
+    instructions.push(".cache:".into());
+    instructions.push("    pop     rdi".into());
+    instructions.push("    mov     [last_key], r8".into());
+    instructions.push("    mov     [last_tile], rdx".into());
+    instructions.push(".found:".into());
+    // Offset in the tile = (y % 64) * 64 + x % 64
+    instructions.push("    mov     rcx, rdi".into());
+    instructions.push("    shr     rcx, 26".into());
+    instructions.push("    and     rcx, 4032".into());
+    instructions.push("    and     rdi, 63".into());
+    instructions.push("    or      rcx, rdi".into());
+    instructions.push(format!("    lea     rax, [rdx+rcx*{}]", bytes));
+    instructions.push("    ret".into());
+
+    // rdi = loc, rsi = val
+    instructions.push("insert_val:".into());
+    instructions.push("    push    rsi".into());
+    instructions.push("    call    cell_ptr".into());
+    instructions.push("    pop     rsi".into());
+    instructions.push(format!("    mov     [rax], {}", value));
+    instructions.push("    ret".into());
+
+    // rdi = loc
+    instructions.push("get_val:".into());
+    instructions.push("    call    cell_ptr".into());
+    instructions.push(format!("    {} [rax]", load));
+    instructions.push("    ret".into());
+
+    // rdi = loc
+    // Returns the cell's value and clears it
+    instructions.push("pop_element:".into());
+    instructions.push("    call    cell_ptr".into());
+    instructions.push("    mov     rcx, rax".into());
+    instructions.push(format!("    {} [rcx]", load));
+    instructions.push(format!("    mov     {} [rcx], 0", size));
+    instructions.push("    ret".into());
+
+    // rdi = loc, rsi = number of cells
+    // Prints each cell followed by a space, then a newline
+    instructions.push("write_cells:".into());
+    instructions.push("    push    r12".into());
+    instructions.push("    push    r13".into());
+    instructions.push("    mov     r12, rdi".into());
+    instructions.push("    mov     r13, rsi".into());
+    instructions.push(".loop:".into());
+    instructions.push("    test    r13, r13".into());
+    instructions.push("    jz      .done".into());
+    instructions.push("    mov     rdi, r12".into());
+    instructions.push("    call    get_val".into());
+    instructions.push("    mov     rdi, rax".into());
+    instructions.push("    mov     rsi, 32".into());
+    instructions.push("    call    print_sep".into());
+    instructions.push("    inc     r12".into());
+    instructions.push("    dec     r13".into());
+    instructions.push("    jmp     .loop".into());
+    instructions.push(".done:".into());
+    instructions.push("    push    10".into());
+    instructions.push("    mov     rax, 1".into());
+    instructions.push("    mov     rdi, 1".into());
+    instructions.push("    mov     rsi, rsp".into());
+    instructions.push("    mov     rdx, 1".into());
+    instructions.push("    syscall".into());
+    instructions.push("    pop     rax".into());
+    instructions.push("    pop     r13".into());
+    instructions.push("    pop     r12".into());
+    instructions.push("    ret".into());
+}
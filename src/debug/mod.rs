@@ -0,0 +1,427 @@
+use std::io::{ BufRead, Write };
+
+use super::{ diag, Error, SourceMap, TokenPos };
+use super::parse::Program;
+use super::sim::Machine;
+
+const HELP: &str = "\
+Commands:
+  step [N]      (s)   run the next N tokens
+  next [N]      (n)   like step, but runs function calls to completion
+  continue      (c)   run until a breakpoint or the end of the program
+  break LINE|FN (b)   stop before LINE runs or when FN is called
+  break               list breakpoints
+  delete N      (d)   remove breakpoint N
+  stack               show the data stack, top last
+  backtrace     (bt)  show the function call stack
+  grid [R]      (g)   show the cells within R (default 2, at most 16) of the pointer
+  where         (w)   show the next token and the pointer
+  quit          (q)   stop debugging
+An empty line repeats the last command.";
+
+const MAX_RADIUS: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Breakpoint {
+    Line(usize),
+    // Index into `Program::functions`
+    Function(usize),
+}
+
+struct Debugger<'a> {
+    program: &'a Program,
+    sources: &'a SourceMap,
+    machine: Machine<'a>,
+    breakpoints: Vec<Breakpoint>,
+    // Set once the program fails; the machine stays at the failing token
+    error: Option<Error>
+}
+
+impl<'a> Debugger<'a> {
+    fn function_name(&self, frame: Option<usize>) -> &'a str {
+        frame.map_or("main", |index| self.program.functions[index].name.as_str())
+    }
+
+    fn breakpoint_name(&self, breakpoint: Breakpoint) -> String {
+        match breakpoint {
+            Breakpoint::Line(line) => format!("line {}", line),
+            Breakpoint::Function(index) => format!("fn {}", self.function_name(Some(index)))
+        }
+    }
+
+    // Whether a breakpoint stops the machine before its next token, given the
+    // line of the token that just ran
+    fn hit_breakpoint(&self, last_line: usize) -> Option<Breakpoint> {
+        let pos = self.machine.pos()?;
+
+        self.breakpoints.iter().copied().find(|breakpoint| match breakpoint {
+            Breakpoint::Line(line) => pos.line == *line && last_line != *line,
+            Breakpoint::Function(index) => self.machine.entering() == Some(*index)
+        })
+    }
+
+    // Steps until `done` holds, a breakpoint is hit or the program stops
+    fn run(&mut self, input: &mut dyn BufRead, output: &mut dyn Write, done: impl Fn(&Machine) -> bool) {
+        loop {
+            if self.error.is_some() || self.machine.is_finished() {
+                return;
+            }
+
+            let last_line = self.machine.pos().map_or(0, |pos| pos.line);
+            if let Err(err) = self.machine.step(input, output) {
+                let _ = output.flush();
+                let _ = write!(output, "{}", diag::render(self.sources, &err));
+                self.error = Some(err);
+                return;
+            }
+
+            if self.machine.is_finished() {
+                let _ = output.flush();
+                let _ = writeln!(output, "Program exited with code {}.", self.machine.exit_code());
+                return;
+            }
+
+            if let Some(breakpoint) = self.hit_breakpoint(last_line) {
+                let _ = output.flush();
+                let _ = writeln!(output, "Breakpoint {} ({}).", self.breakpoint_index(breakpoint) + 1, self.breakpoint_name(breakpoint));
+                return;
+            }
+
+            if done(&self.machine) {
+                return;
+            }
+        }
+    }
+
+    fn breakpoint_index(&self, breakpoint: Breakpoint) -> usize {
+        self.breakpoints.iter().position(|b| *b == breakpoint).unwrap()
+    }
+
+    fn show_source(&self, output: &mut dyn Write, pos: TokenPos) {
+        let _ = write!(output, "{}", diag::render_snippet(self.sources, pos));
+    }
+
+    fn show_where(&self, output: &mut dyn Write) {
+        let (x, y, _) = self.machine.mem_addr;
+
+        match self.machine.pos() {
+            Some(pos) => {
+                let function = self.function_name(self.machine.call_stack.last().map(|frame| frame.function));
+                let _ = writeln!(output, "At {} in {}, pointer at ({}, {}):", pos, function, x, y);
+                self.show_source(output, pos);
+            },
+            None => {
+                let _ = writeln!(output, "The program has finished, pointer at ({}, {}).", x, y);
+            }
+        }
+    }
+
+    fn show_stack(&self, output: &mut dyn Write) {
+        if self.machine.stack.is_empty() {
+            let _ = writeln!(output, "The stack is empty.");
+        } else {
+            let values: Vec<String> = self.machine.stack.iter().map(|value| value.to_string()).collect();
+            let _ = writeln!(output, "[{}]", values.join(", "));
+        }
+    }
+
+    fn show_backtrace(&self, output: &mut dyn Write) {
+        let frames = &self.machine.call_stack;
+
+        // The innermost function is where the next token is, every other one
+        // is where its callee was called from
+        let mut pos = self.machine.pos();
+        for (depth, frame) in frames.iter().rev().enumerate() {
+            let at = pos.map_or(String::from("the end"), |pos| pos.to_string());
+            let _ = writeln!(output, "#{} {} at {}", depth, self.function_name(Some(frame.function)), at);
+            pos = Some(frame.call_pos);
+        }
+
+        let at = pos.map_or(String::from("the end"), |pos| pos.to_string());
+        let _ = writeln!(output, "#{} main at {}", frames.len(), at);
+    }
+
+    // Cells within `radius` of the pointer, which is shown in brackets
+    fn show_grid(&self, output: &mut dyn Write, radius: u32) {
+        let (px, py, _) = self.machine.mem_addr;
+        let xs = px.saturating_sub(radius)..=px.saturating_add(radius);
+        let ys = py.saturating_sub(radius)..=py.saturating_add(radius);
+
+        let width = ys.clone()
+            .flat_map(|y| xs.clone().map(move |x| (x, y)))
+            .map(|(x, y)| self.machine.cell(x, y).to_string().len())
+            .chain(xs.clone().map(|x| x.to_string().len()))
+            .max()
+            .unwrap_or(1);
+        let label = ys.clone().map(|y| y.to_string().len()).max().unwrap_or(1);
+
+        let mut header = " ".repeat(label + 1);
+        for x in xs.clone() {
+            header.push_str(&format!(" {:>width$} ", x, width = width));
+        }
+        let _ = writeln!(output, "{}", header.trim_end());
+
+        for y in ys {
+            let mut row = format!("{:>label$} ", y, label = label);
+            for x in xs.clone() {
+                let value = self.machine.cell(x, y);
+                if (x, y) == (px, py) {
+                    row.push_str(&format!("[{:>width$}]", value, width = width));
+                } else {
+                    row.push_str(&format!(" {:>width$} ", value, width = width));
+                }
+            }
+            let _ = writeln!(output, "{}", row.trim_end());
+        }
+    }
+
+    fn add_breakpoint(&mut self, output: &mut dyn Write, target: &str) {
+        let breakpoint = match target.parse::<usize>() {
+            Ok(0) => {
+                let _ = writeln!(output, "Lines start at 1.");
+                return;
+            },
+            Ok(line) if !self.machine.has_code_on(line) => {
+                let _ = writeln!(output, "No code on line {}.", line);
+                return;
+            },
+            Ok(line) => Breakpoint::Line(line),
+            Err(_) => match self.program.functions.iter().position(|function| function.name == target) {
+                Some(index) => Breakpoint::Function(index),
+                None => {
+                    let _ = writeln!(output, "No function named `{}`.", target);
+                    return;
+                }
+            }
+        };
+
+        if !self.breakpoints.contains(&breakpoint) {
+            self.breakpoints.push(breakpoint);
+        }
+        let _ = writeln!(output, "Breakpoint {} ({}).", self.breakpoint_index(breakpoint) + 1, self.breakpoint_name(breakpoint));
+    }
+
+    fn list_breakpoints(&self, output: &mut dyn Write) {
+        if self.breakpoints.is_empty() {
+            let _ = writeln!(output, "No breakpoints.");
+        }
+
+        for (index, breakpoint) in self.breakpoints.iter().enumerate() {
+            let _ = writeln!(output, "{}: {}", index + 1, self.breakpoint_name(*breakpoint));
+        }
+    }
+}
+
+// Parses an optional count argument, reporting anything that isn't one
+fn count(arg: Option<&str>, default: u64, output: &mut dyn Write) -> Option<u64> {
+    match arg.map(|arg| arg.parse::<u64>()) {
+        None => Some(default),
+        Some(Ok(n)) => Some(n),
+        Some(Err(_)) => {
+            let _ = writeln!(output, "Expected a number, got `{}`.", arg.unwrap());
+            None
+        }
+    }
+}
+
+// Runs the program under an interactive debugger reading commands from
+// `input`, which the program also reads its own input from. Returns the
+// program's exit code if it finished, or 1 if it failed.
+pub fn debug(program: &Program, sources: &SourceMap, input: &mut dyn BufRead, output: &mut dyn Write) -> i32 {
+    let mut debugger = Debugger {
+        program,
+        sources,
+        machine: Machine::new(program),
+        breakpoints: Vec::new(),
+        error: None
+    };
+
+    let _ = writeln!(output, "Type `help` for a list of commands.");
+    debugger.show_where(output);
+
+    let mut last_command = String::new();
+
+    loop {
+        let _ = write!(output, "(lattice) ");
+        let _ = output.flush();
+
+        let mut line = String::new();
+        match input.read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => { }
+        }
+
+        if line.trim().is_empty() {
+            line = last_command.clone();
+        } else {
+            last_command = line.clone();
+        }
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (command, arg) = match words.as_slice() {
+            [] => continue,
+            [command] => (*command, None),
+            [command, arg] => (*command, Some(*arg)),
+            _ => {
+                let _ = writeln!(output, "Too many arguments; type `help` for a list of commands.");
+                continue;
+            }
+        };
+
+        match command {
+            "s" | "step" | "n" | "next" | "c" | "continue" if debugger.error.is_some() => {
+                let _ = writeln!(output, "The program stopped with an error.");
+            },
+            "s" | "step" | "n" | "next" | "c" | "continue" if debugger.machine.is_finished() => {
+                let _ = writeln!(output, "The program has finished.");
+            },
+            "s" | "step" => {
+                if let Some(n) = count(arg, 1, output) {
+                    for _ in 0..n {
+                        debugger.run(input, output, |_| true);
+                        if debugger.error.is_some() || debugger.machine.is_finished() {
+                            break;
+                        }
+                    }
+                    debugger.show_where(output);
+                }
+            },
+            "n" | "next" => {
+                if let Some(n) = count(arg, 1, output) {
+                    for _ in 0..n {
+                        let depth = debugger.machine.call_stack.len();
+                        debugger.run(input, output, |machine| machine.call_stack.len() <= depth);
+                        if debugger.error.is_some() || debugger.machine.is_finished() {
+                            break;
+                        }
+                    }
+                    debugger.show_where(output);
+                }
+            },
+            "c" | "continue" => {
+                debugger.run(input, output, |_| false);
+                debugger.show_where(output);
+            },
+            "b" | "break" => match arg {
+                Some(target) => debugger.add_breakpoint(output, target),
+                None => debugger.list_breakpoints(output)
+            },
+            "d" | "delete" => match arg.and_then(|arg| arg.parse::<usize>().ok()) {
+                Some(n) if n >= 1 && n <= debugger.breakpoints.len() => {
+                    let breakpoint = debugger.breakpoints.remove(n - 1);
+                    let _ = writeln!(output, "Deleted breakpoint {} ({}).", n, debugger.breakpoint_name(breakpoint));
+                },
+                _ => {
+                    let _ = writeln!(output, "Expected the number of a breakpoint; `break` lists them.");
+                }
+            },
+            "stack" => debugger.show_stack(output),
+            "bt" | "backtrace" => debugger.show_backtrace(output),
+            "g" | "grid" => {
+                if let Some(radius) = count(arg, 2, output) {
+                    debugger.show_grid(output, radius.min(MAX_RADIUS as u64) as u32);
+                }
+            },
+            "w" | "where" => debugger.show_where(output),
+            "h" | "help" => {
+                let _ = writeln!(output, "{}", HELP);
+            },
+            "q" | "quit" => break,
+            _ => {
+                let _ = writeln!(output, "Unknown command `{}`; type `help` for a list of commands.", command);
+            }
+        }
+    }
+
+    let _ = output.flush();
+
+    match (&debugger.error, debugger.machine.is_finished()) {
+        (Some(_), _) => 1,
+        (None, true) => debugger.machine.exit_code(),
+        (None, false) => 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_program;
+
+    // Runs `commands` through the debugger, returning what it printed and
+    // the exit code
+    fn session(src: &str, commands: &str) -> (String, i32) {
+        let (sources, program) = test_program(src);
+
+        let mut output: Vec<u8> = Vec::new();
+        let code = debug(&program, &sources, &mut commands.as_bytes(), &mut output);
+
+        (String::from_utf8(output).unwrap(), code)
+    }
+
+    const PROGRAM: &str = "\
+// Should print 3 and 7
+fn add
+    +
+end
+
+1 2 add print
+
+3 4 add print";
+
+    #[test]
+    fn breakpoints_need_code() {
+        let (output, _) = session(PROGRAM, "break 1\nbreak 7\nbreak 0\nbreak 42\nbreak\n");
+
+        assert_eq!(output.matches("No code on line").count(), 3);
+        assert!(output.contains("No code on line 1."));
+        assert!(output.contains("No code on line 7."));
+        assert!(output.contains("Lines start at 1."));
+        assert!(output.contains("No code on line 42."));
+        assert!(output.contains("No breakpoints."));
+    }
+
+    #[test]
+    fn continue_to_line_and_function_breakpoints() {
+        let (output, code) = session(PROGRAM, "break 8\nbreak add\nc\nbt\nc\nc\nstack\ndelete 1\nc\nc\n");
+
+        assert_eq!(code, 0);
+        assert_eq!(output, "\
+Type `help` for a list of commands.
+At 6:1 in main, pointer at (0, 0):
+6 | 1 2 add print
+  | ^
+(lattice) Breakpoint 1 (line 8).
+(lattice) Breakpoint 2 (fn add).
+(lattice) Breakpoint 2 (fn add).
+At 3:5 in add, pointer at (0, 0):
+3 |     +
+  |     ^
+(lattice) #0 add at 3:5
+#1 main at 6:5
+(lattice) 3
+Breakpoint 1 (line 8).
+At 8:1 in main, pointer at (0, 0):
+8 | 3 4 add print
+  | ^
+(lattice) Breakpoint 2 (fn add).
+At 3:5 in add, pointer at (0, 0):
+3 |     +
+  |     ^
+(lattice) [3, 4]
+(lattice) Deleted breakpoint 1 (line 8).
+(lattice) 7
+Program exited with code 0.
+The program has finished, pointer at (0, 0).
+(lattice) The program has finished.
+(lattice) ");
+    }
+
+    #[test]
+    fn step_and_repeat() {
+        let (output, code) = session("5 6 +", "s\n\nstack\nn 5\n");
+
+        assert_eq!(code, 11);
+        assert!(output.contains("(lattice) [5, 6]\n"));
+        assert!(output.ends_with("Program exited with code 11.\nThe program has finished, pointer at (0, 0).\n(lattice) "));
+    }
+}
@@ -0,0 +1,187 @@
+use std::fmt::Write;
+
+use super::{ Diagnostic, Error, SourceMap, TokenPos };
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorFormat {
+    Human,
+    // One JSON object per line, for editor integrations
+    Json,
+}
+
+impl std::str::FromStr for ErrorFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(ErrorFormat::Human),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(Error::without_pos(format!("Unknown error format: {}", s)))
+        }
+    }
+}
+
+// Renders an error with the offending source line and an underline:
+//
+//   error: Unknown word `fo`
+//    --> tests/foo.lat:2:5
+//     |
+//   2 |   3 fo print
+//     |     ^^
+//     = help: Did you mean `foo`?
+//
+// Runtime errors additionally list the stack and grid pointer at the time of
+// the failure, and tool errors the command that was run and its stderr.
+pub fn render(sources: &SourceMap, err: &Error) -> String {
+    match err {
+        Error::Diagnostic(diagnostic) => render_diagnostic(sources, "error", diagnostic),
+        Error::RuntimeError(err) => {
+            let diagnostic = Diagnostic {
+                msg: err.msg.clone(),
+                pos: Some(err.pos),
+                notes: vec![
+                    format!("stack (top last): {:?}", err.stack),
+                    format!("grid pointer: ({}, {})", err.mem_addr.0, err.mem_addr.1),
+                ],
+                help: None
+            };
+
+            render_diagnostic(sources, "runtime error", &diagnostic)
+        },
+        Error::ToolError(err) => {
+            let mut notes = vec![format!("command: {}", err.command)];
+            notes.extend(err.stderr.lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| line.to_string()));
+
+            let diagnostic = Diagnostic { msg: err.msg.clone(), pos: None, notes, help: None };
+
+            render_diagnostic(sources, "error", &diagnostic)
+        }
+    }
+}
+
+fn render_diagnostic(sources: &SourceMap, level: &str, err: &Diagnostic) -> String {
+    let mut out = format!("{}: {}\n", level, err.msg);
+
+    let gutter = match err.pos {
+        Some(pos) => {
+            let file = sources.get(pos.file);
+            let gutter = " ".repeat(pos.line.to_string().len());
+
+            let _ = writeln!(out, "{}--> {}:{}:{}", gutter, file.name, pos.line, pos.col);
+            let _ = writeln!(out, "{} |", gutter);
+            out.push_str(&render_snippet(sources, pos));
+
+            gutter
+        },
+        None => String::new()
+    };
+
+    for note in &err.notes {
+        let _ = writeln!(out, "{} = note: {}", gutter, note);
+    }
+    if let Some(help) = &err.help {
+        let _ = writeln!(out, "{} = help: {}", gutter, help);
+    }
+
+    out
+}
+
+// Renders the source line of `pos` with the token underlined:
+//
+//   2 |   3 fo print
+//     |     ^^
+pub fn render_snippet(sources: &SourceMap, pos: TokenPos) -> String {
+    let file = sources.get(pos.file);
+    let line_start = file.text[..pos.offset].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = file.text[pos.offset..].find('\n').map_or(file.text.len(), |i| pos.offset + i);
+    let line = &file.text[line_start..line_end];
+
+    let gutter = " ".repeat(pos.line.to_string().len());
+
+    // Keep tabs in the padding so the carets line up with the source
+    let padding: String = file.text[line_start..pos.offset].chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let span_end = (pos.offset + pos.len).min(line_end);
+    let underline = file.text[pos.offset..span_end].chars().count().max(1);
+
+    format!("{} | {}\n{} | {}{}\n", pos.line, line.trim_end(), gutter, padding, "^".repeat(underline))
+}
+
+pub fn render_json(sources: &SourceMap, err: &Error) -> String {
+    let (level, notes, help) = match err {
+        Error::Diagnostic(diagnostic) => ("error", diagnostic.notes.as_slice(), &diagnostic.help),
+        Error::RuntimeError(_) => ("runtime error", &[][..], &None),
+        Error::ToolError(_) => ("error", &[][..], &None)
+    };
+
+    let mut out = format!("{{\"level\":\"{}\",\"message\":{}", level, json_string(err.msg()));
+
+    if let Some(pos) = err.pos() {
+        let _ = write!(out, ",\"file\":{},\"line\":{},\"column\":{},\"offset\":{},\"length\":{}",
+            json_string(&sources.get(pos.file).name), pos.line, pos.col, pos.offset, pos.len);
+    }
+
+    if let Error::RuntimeError(err) = err {
+        let stack: Vec<String> = err.stack.iter().map(|v| v.to_string()).collect();
+        let _ = write!(out, ",\"stack\":[{}],\"pointer\":[{},{}]", stack.join(","), err.mem_addr.0, err.mem_addr.1);
+    }
+
+    if let Error::ToolError(err) = err {
+        let status = err.status.map_or("null".to_string(), |code| code.to_string());
+        let _ = write!(out, ",\"stage\":{},\"command\":{},\"status\":{},\"stderr\":{}",
+            json_string(&err.stage), json_string(&err.command), status, json_string(&err.stderr));
+    }
+
+    let notes: Vec<String> = notes.iter().map(|n| json_string(n)).collect();
+    let _ = write!(out, ",\"notes\":[{}]", notes.join(","));
+
+    match help {
+        Some(help) => { let _ = write!(out, ",\"help\":{}}}", json_string(help)); },
+        None => out.push_str(",\"help\":null}")
+    }
+
+    out
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); },
+            c => out.push(c)
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+// Prints every error to stderr in the requested format
+pub fn report(sources: &SourceMap, errors: &[Error], format: ErrorFormat) {
+    match format {
+        ErrorFormat::Human => {
+            for err in errors {
+                eprintln!("{}", render(sources, err));
+            }
+
+            if errors.len() > 1 {
+                eprintln!("error: aborting due to {} previous errors", errors.len());
+            }
+        },
+        ErrorFormat::Json => {
+            for err in errors {
+                eprintln!("{}", render_json(sources, err));
+            }
+        }
+    }
+}
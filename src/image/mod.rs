@@ -0,0 +1,757 @@
+use std::fs::{ self, File };
+use std::io::{ BufWriter, Write };
+use std::path::Path;
+
+use std::collections::{ BTreeMap, HashMap };
+
+use super::{ Error, Token };
+use super::sim::{ Machine, Step };
+
+// Largest region of the grid that is rendered, in cells
+const MAX_CELLS: u64 = 1 << 24;
+// Cells are scaled up so small grids still produce a visible picture
+const TARGET_SIZE: u32 = 512;
+const MAX_SCALE: u32 = 16;
+
+const BACKGROUND: [u8; 3] = [255, 255, 255];
+const POINTER: [u8; 3] = [220, 40, 40];
+
+// Time each frame of an animation is shown for, in hundredths of a second
+const FRAME_DELAY: u16 = 10;
+// Limits on what a recording keeps in memory until it is saved
+const MAX_FRAMES: usize = 1 << 16;
+const MAX_CHANGES: usize = 1 << 24;
+
+// An RGB image, row by row from the top left
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<[u8; 3]>
+}
+
+// The cells of a region of the grid at one point in time
+#[derive(Debug, Clone)]
+pub struct Frame {
+    // `(x, y, width, height)` in cells
+    pub region: (u32, u32, u32, u32),
+    // Row by row from the top left
+    pub cells: Vec<u64>,
+    pub pointer: (u32, u32)
+}
+
+impl Frame {
+    // Copies the smallest rectangle of cells holding every cell that was ever
+    // stored, and the pointer
+    pub fn capture(machine: &Machine) -> Result<Frame, Error> {
+        let (px, py, _) = machine.mem_addr;
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (px, py, px, py);
+
+        for loc in machine.mem.keys() {
+            let (x, y) = (*loc as u32, (*loc >> 32) as u32);
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+        }
+
+        let region = (min_x, min_y, (max_x - min_x).saturating_add(1), (max_y - min_y).saturating_add(1));
+        check_region(region)?;
+
+        let (left, top, width, height) = region;
+        let cells = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (left + x, top + y)))
+            .map(|(x, y)| machine.cell(x, y))
+            .collect();
+
+        Ok(Frame { region, cells, pointer: (px, py) })
+    }
+
+    // Cells outside the captured region were never stored
+    fn cell(&self, x: u32, y: u32) -> u64 {
+        let (left, top, width, height) = self.region;
+        if x < left || y < top || x - left >= width || y - top >= height {
+            return 0;
+        }
+
+        self.cells[((y - top) as usize) * width as usize + (x - left) as usize]
+    }
+}
+
+fn check_region(region: (u32, u32, u32, u32)) -> Result<(), Error> {
+    let (_, _, width, height) = region;
+
+    if width as u64 * height as u64 > MAX_CELLS {
+        return Err(Error::without_pos(format!(
+            "The grid region to render is {}x{} cells, which is too large to draw.", width, height
+        )));
+    }
+
+    Ok(())
+}
+
+// Draws the cells of `frame` in `region`, one square per cell: 0 is white and
+// larger values are darker, up to black for `max`. The pointer is drawn in red.
+pub fn render(frame: &Frame, region: (u32, u32, u32, u32), max: u64) -> Image {
+    let (left, top, width, height) = region;
+    let max = max.max(1);
+    let scale = scale_for(width, height);
+
+    let mut image = Image {
+        width: width * scale,
+        height: height * scale,
+        pixels: Vec::with_capacity((width * scale) as usize * (height * scale) as usize)
+    };
+
+    for y in top..top + height {
+        let row: Vec<[u8; 3]> = (left..left + width)
+            .map(|x| {
+                if (x, y) == frame.pointer {
+                    return POINTER;
+                }
+
+                match frame.cell(x, y) {
+                    0 => BACKGROUND,
+                    value => {
+                        let shade = 200 - (value.min(max) as u128 * 200 / max as u128) as u8;
+                        [shade, shade, shade]
+                    }
+                }
+            })
+            .flat_map(|pixel| std::iter::repeat(pixel).take(scale as usize))
+            .collect();
+
+        for _ in 0..scale {
+            image.pixels.extend_from_slice(&row);
+        }
+    }
+
+    image
+}
+
+// Pixels per cell for a region of the grid
+fn scale_for(width: u32, height: u32) -> u32 {
+    (TARGET_SIZE / width.max(height)).clamp(1, MAX_SCALE)
+}
+
+// Draws every cell that was ever stored, and the pointer
+pub fn render_grid(machine: &Machine) -> Result<Image, Error> {
+    let frame = Frame::capture(machine)?;
+    let max = frame.cells.iter().copied().max().unwrap_or(0);
+
+    Ok(render(&frame, frame.region, max))
+}
+
+// Captures frames while a program runs (see `Machine::run_with`): every
+// `every` steps, or after each `write` if it isn't set. Only the cells that
+// changed since the previous frame are kept; frames are drawn when saved.
+#[derive(Debug, Default)]
+pub struct Recorder {
+    pub every: Option<u64>,
+    steps: u64,
+    // The grid as of the last frame
+    last: BTreeMap<u64, u64>,
+    // `(loc, value)` of every cell that changed, frame after frame
+    changes: Vec<(u64, u64)>,
+    // Where each frame's changes end, and its pointer
+    frames: Vec<(usize, (u32, u32))>,
+    // `(min_x, min_y, max_x, max_y)` of every cell and pointer in a frame
+    bounds: Option<(u32, u32, u32, u32)>,
+    max: u64
+}
+
+impl Recorder {
+    pub fn new(every: Option<u64>) -> Self {
+        Recorder { every, ..Default::default() }
+    }
+
+    pub fn observe(&mut self, machine: &Machine, step: &Step) -> Result<(), Error> {
+        self.steps += 1;
+
+        let due = match self.every {
+            Some(every) => self.steps % every == 0,
+            None => matches!(step.token, Some(Token::Write))
+        };
+        if due {
+            self.capture(machine)?;
+        }
+
+        Ok(())
+    }
+
+    fn capture(&mut self, machine: &Machine) -> Result<(), Error> {
+        if self.frames.len() >= MAX_FRAMES || self.changes.len() >= MAX_CHANGES {
+            return Err(Error::without_pos(format!(
+                "The recording is too large to keep after {} frames; record fewer with `--record-every`.", self.frames.len()
+            )));
+        }
+
+        let (px, py, _) = machine.mem_addr;
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = self.bounds.unwrap_or((px, py, px, py));
+
+        for (loc, value) in &machine.mem {
+            let (x, y) = (*loc as u32, (*loc >> 32) as u32);
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+
+            // Cells are never removed, only stored or changed
+            if self.last.insert(*loc, *value) != Some(*value) {
+                self.changes.push((*loc, *value));
+                self.max = self.max.max(*value);
+            }
+        }
+
+        let bounds = (min_x.min(px), min_y.min(py), max_x.max(px), max_y.max(py));
+        check_region(region_of(bounds))?;
+
+        self.bounds = Some(bounds);
+        self.frames.push((self.changes.len(), (px, py)));
+
+        Ok(())
+    }
+
+    // Adds a frame of the final grid and draws every frame over the region
+    // they cover together, writing each as soon as it is drawn. A `.gif` path
+    // gets an animation; otherwise frames are numbered images next to it,
+    // e.g. `out-0001.png` for `out.png`.
+    pub fn save(mut self, machine: &Machine, path: &Path) -> Result<(), Error> {
+        let format = ImageFormat::from_path(path)?;
+        self.capture(machine)?;
+
+        let region = region_of(self.bounds.unwrap());
+        let (left, top, width, height) = region;
+        let mut canvas = Frame { region, cells: vec![0; width as usize * height as usize], pointer: (0, 0) };
+
+        let mut gif = match format {
+            ImageFormat::Gif => {
+                let scale = scale_for(width, height);
+                let mut file = create_file(path)?;
+                write_to(&mut file, path, &gif_header(width * scale, height * scale)?)?;
+                Some(file)
+            },
+            _ => None
+        };
+
+        let stem = path.file_stem().map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+        let digits = self.frames.len().to_string().len().max(4);
+
+        let mut start = 0;
+        for (index, (end, pointer)) in self.frames.iter().enumerate() {
+            for (loc, value) in &self.changes[start..*end] {
+                let (x, y) = (*loc as u32 - left, (*loc >> 32) as u32 - top);
+                canvas.cells[y as usize * width as usize + x as usize] = *value;
+            }
+            canvas.pointer = *pointer;
+            start = *end;
+
+            let image = render(&canvas, region, self.max);
+            match &mut gif {
+                Some(file) => write_to(file, path, &gif_frame(&image)?)?,
+                None => {
+                    let name = format!("{}-{:0digits$}.{}", stem, index + 1, path.extension().unwrap().to_string_lossy(), digits = digits);
+                    save(&image, &path.with_file_name(name))?;
+                }
+            }
+        }
+
+        if let Some(mut file) = gif {
+            write_to(&mut file, path, &[GIF_TRAILER])?;
+            file.flush().map_err(|err| Error::without_pos(format!("Unable to write {}: {}", path.display(), err)))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn region_of(bounds: (u32, u32, u32, u32)) -> (u32, u32, u32, u32) {
+    let (min_x, min_y, max_x, max_y) = bounds;
+    (min_x, min_y, (max_x - min_x).saturating_add(1), (max_y - min_y).saturating_add(1))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Ppm,
+    Png,
+    Gif,
+}
+
+impl ImageFormat {
+    // Picks the format from the extension of `path`
+    pub fn from_path(path: &Path) -> Result<Self, Error> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("ppm") => Ok(ImageFormat::Ppm),
+            Some("png") => Ok(ImageFormat::Png),
+            Some("gif") => Ok(ImageFormat::Gif),
+            _ => Err(Error::without_pos(format!("Unable to write {}: images must end in .ppm, .png or .gif.", path.display())))
+        }
+    }
+}
+
+// Writes the image as a PPM, PNG or GIF file, depending on the extension of `path`
+pub fn save(image: &Image, path: &Path) -> Result<(), Error> {
+    let bytes = match ImageFormat::from_path(path)? {
+        ImageFormat::Ppm => to_ppm(image),
+        ImageFormat::Png => to_png(image),
+        ImageFormat::Gif => to_gif(std::slice::from_ref(image))?
+    };
+
+    write_file(path, bytes)
+}
+
+fn write_file(path: &Path, bytes: Vec<u8>) -> Result<(), Error> {
+    let mut file = create_file(path)?;
+    write_to(&mut file, path, &bytes)?;
+    file.flush().map_err(|err| Error::without_pos(format!("Unable to write {}: {}", path.display(), err)))
+}
+
+fn create_file(path: &Path) -> Result<BufWriter<File>, Error> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)
+            .map_err(|err| Error::without_pos(format!("Failed to create {}: {}", dir.display(), err)))?;
+    }
+
+    File::create(path)
+        .map(BufWriter::new)
+        .map_err(|err| Error::without_pos(format!("Unable to write {}: {}", path.display(), err)))
+}
+
+fn write_to(file: &mut BufWriter<File>, path: &Path, bytes: &[u8]) -> Result<(), Error> {
+    file.write_all(bytes)
+        .map_err(|err| Error::without_pos(format!("Unable to write {}: {}", path.display(), err)))
+}
+
+pub fn to_ppm(image: &Image) -> Vec<u8> {
+    let mut out = format!("P6\n{} {}\n255\n", image.width, image.height).into_bytes();
+    out.extend(image.pixels.iter().flatten());
+    out
+}
+
+pub fn to_png(image: &Image) -> Vec<u8> {
+    // Every row starts with filter type 0 (none)
+    let stride = image.width as usize * 3 + 1;
+    let mut raw: Vec<u8> = Vec::with_capacity(stride * image.height as usize);
+    for row in image.pixels.chunks(image.width.max(1) as usize) {
+        raw.push(0);
+        raw.extend(row.iter().flatten());
+    }
+
+    let mut header: Vec<u8> = Vec::new();
+    header.extend_from_slice(&image.width.to_be_bytes());
+    header.extend_from_slice(&image.height.to_be_bytes());
+    // 8 bits per channel, RGB, default compression, filtering and no interlacing
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut out: Vec<u8> = b"\x89PNG\r\n\x1a\n".to_vec();
+    png_chunk(&mut out, b"IHDR", &header);
+    png_chunk(&mut out, b"IDAT", &zlib(&raw, stride));
+    png_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+
+    let mut crc = !0u32;
+    for byte in kind.iter().chain(data) {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    out.extend_from_slice(&(!crc).to_be_bytes());
+}
+
+// Writes bits least significant first, as deflate expects
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bit: u32,
+    len: u32
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u32, count: u32) {
+        self.bit |= value << self.len;
+        self.len += count;
+        while self.len >= 8 {
+            self.bytes.push(self.bit as u8);
+            self.bit >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    // Huffman codes are stored most significant bit first
+    fn code(&mut self, code: u32, count: u32) {
+        let reversed = code.reverse_bits() >> (32 - count);
+        self.bits(reversed, count);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.bytes.push(self.bit as u8);
+        }
+        self.bytes
+    }
+}
+
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577
+];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+// Writes a literal or length symbol with the fixed Huffman code
+fn fixed_symbol(out: &mut BitWriter, symbol: u32) {
+    match symbol {
+        0..=143 => out.code(0x30 + symbol, 8),
+        144..=255 => out.code(0x190 + symbol - 144, 9),
+        256..=279 => out.code(symbol - 256, 7),
+        _ => out.code(0xC0 + symbol - 280, 8)
+    }
+}
+
+// Compresses `data` as a zlib stream of one fixed Huffman block. Images are
+// mostly runs of one color and rows like the one above, so only matches at
+// the distance of one pixel and of one row (`stride`) are looked for.
+fn zlib(data: &[u8], stride: usize) -> Vec<u8> {
+    let mut out = BitWriter::default();
+    // Final block, fixed Huffman codes
+    out.bits(1, 1);
+    out.bits(1, 2);
+
+    let distances: Vec<usize> = [3, stride].iter().copied().filter(|d| *d <= 32768).collect();
+
+    let mut i = 0;
+    while i < data.len() {
+        let (length, distance) = distances.iter()
+            .filter(|d| **d <= i)
+            .map(|d| {
+                let length = (0..258.min(data.len() - i))
+                    .take_while(|k| data[i + k] == data[i + k - d])
+                    .count();
+                (length, *d)
+            })
+            .max()
+            .unwrap_or((0, 0));
+
+        if length < 3 {
+            fixed_symbol(&mut out, data[i] as u32);
+            i += 1;
+            continue;
+        }
+
+        let code = LENGTH_BASES.iter().rposition(|base| *base as usize <= length).unwrap();
+        fixed_symbol(&mut out, 257 + code as u32);
+        out.bits((length - LENGTH_BASES[code] as usize) as u32, LENGTH_EXTRA[code] as u32);
+
+        let code = DIST_BASES.iter().rposition(|base| *base as usize <= distance).unwrap();
+        out.code(code as u32, 5);
+        out.bits((distance - DIST_BASES[code] as usize) as u32, DIST_EXTRA[code] as u32);
+
+        i += length;
+    }
+
+    // End of block
+    fixed_symbol(&mut out, 256);
+
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    let mut stream = vec![0x78, 0x01];
+    stream.extend(out.finish());
+    stream.extend_from_slice(&((b << 16) | a).to_be_bytes());
+    stream
+}
+
+const GIF_TRAILER: u8 = 0x3B;
+
+// Writes the images, which must be the same size, as an animated GIF that
+// loops forever
+pub fn to_gif(images: &[Image]) -> Result<Vec<u8>, Error> {
+    let (width, height) = images.first().map_or((1, 1), |image| (image.width, image.height));
+
+    let mut out = gif_header(width, height)?;
+    for image in images {
+        out.extend(gif_frame(image)?);
+    }
+    out.push(GIF_TRAILER);
+
+    Ok(out)
+}
+
+// Every color `render` draws with: the background, the pointer and the
+// shades of gray from light to black
+fn palette() -> Vec<[u8; 3]> {
+    let mut colors = vec![BACKGROUND, POINTER];
+    colors.extend((0..=200).rev().map(|shade| [shade, shade, shade]));
+    colors
+}
+
+fn gif_header(width: u32, height: u32) -> Result<Vec<u8>, Error> {
+    if width > u16::MAX as u32 || height > u16::MAX as u32 {
+        return Err(Error::without_pos(format!("A {}x{} image is too large for a GIF.", width, height)));
+    }
+
+    let mut colors = palette();
+    colors.resize(256, [0, 0, 0]);
+
+    let mut out: Vec<u8> = b"GIF89a".to_vec();
+    out.extend_from_slice(&(width as u16).to_le_bytes());
+    out.extend_from_slice(&(height as u16).to_le_bytes());
+    // Global color table of 256 entries, 8 bits per primary color
+    out.extend_from_slice(&[0xF7, 0, 0]);
+    out.extend(colors.iter().flatten());
+
+    // Loop forever
+    out.extend_from_slice(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00");
+
+    Ok(out)
+}
+
+fn gif_frame(image: &Image) -> Result<Vec<u8>, Error> {
+    let palette: HashMap<[u8; 3], u8> = palette().into_iter().enumerate().map(|(index, color)| (color, index as u8)).collect();
+    let indices = image.pixels.iter()
+        .map(|pixel| palette.get(pixel).copied())
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| Error::without_pos("The image has colors that aren't in the GIF palette."))?;
+
+    // Graphic control extension with the frame's delay
+    let mut out: Vec<u8> = vec![0x21, 0xF9, 0x04, 0x00];
+    out.extend_from_slice(&FRAME_DELAY.to_le_bytes());
+    out.extend_from_slice(&[0x00, 0x00]);
+
+    // Image descriptor covering the whole screen, without a local color table
+    out.push(0x2C);
+    out.extend_from_slice(&[0, 0, 0, 0]);
+    out.extend_from_slice(&(image.width as u16).to_le_bytes());
+    out.extend_from_slice(&(image.height as u16).to_le_bytes());
+    out.push(0);
+
+    out.push(8);
+    for block in lzw(&indices).chunks(255) {
+        out.push(block.len() as u8);
+        out.extend_from_slice(block);
+    }
+    out.push(0);
+
+    Ok(out)
+}
+
+// Compresses 8-bit palette indices with GIF's variable-width LZW
+fn lzw(indices: &[u8]) -> Vec<u8> {
+    const CLEAR: u32 = 256;
+    const END: u32 = 257;
+    const MAX_CODES: u32 = 4096;
+
+    let mut out = BitWriter::default();
+    let mut table: HashMap<(u32, u8), u32> = HashMap::new();
+    let mut next = END + 1;
+    let mut size = 9;
+
+    out.bits(CLEAR, size);
+
+    let mut prefix: Option<u32> = None;
+    for byte in indices {
+        let code = match prefix {
+            Some(code) => code,
+            None => {
+                prefix = Some(*byte as u32);
+                continue;
+            }
+        };
+
+        if let Some(longer) = table.get(&(code, *byte)) {
+            prefix = Some(*longer);
+            continue;
+        }
+
+        out.bits(code, size);
+
+        // The decoder adds each entry one code later than the encoder, so
+        // the code width grows one code later too
+        if next == 1 << size && size < 12 {
+            size += 1;
+        }
+
+        if next < MAX_CODES {
+            table.insert((code, *byte), next);
+            next += 1;
+        } else {
+            out.bits(CLEAR, size);
+            table.clear();
+            next = END + 1;
+            size = 9;
+        }
+
+        prefix = Some(*byte as u32);
+    }
+
+    if let Some(code) = prefix {
+        out.bits(code, size);
+        if next == 1 << size && size < 12 {
+            size += 1;
+        }
+    }
+    out.bits(END, size);
+
+    out.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::test_program;
+
+    fn record(src: &str, every: Option<u64>, path: &Path) -> usize {
+        let (_, program) = test_program(src);
+
+        let mut machine = Machine::new(&program);
+        let mut recorder = Recorder::new(every);
+        machine.run_with(&mut &b""[..], &mut Vec::new(), &mut |machine, step| recorder.observe(machine, step)).unwrap();
+
+        let frames = recorder.frames.len();
+        recorder.save(&machine, path).unwrap();
+        frames
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lattice-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    // Walks the blocks after the header and global color table
+    fn gif_frames(gif: &[u8]) -> usize {
+        let mut i = 13 + 3 * 256;
+        let mut frames = 0;
+
+        loop {
+            match gif[i] {
+                0x21 => i += 2,
+                0x2C => {
+                    frames += 1;
+                    i += 11;
+                },
+                GIF_TRAILER => return frames,
+                byte => panic!("unexpected block {:#x} at {}", byte, i)
+            }
+            while gif[i] != 0 {
+                i += gif[i] as usize + 1;
+            }
+            i += 1;
+        }
+    }
+
+    // Cells 1 and 2 on the top row, with the pointer below the 2
+    fn two_by_two() -> Image {
+        let (_, program) = test_program("1 . 1 r 2 . 1 d");
+
+        let mut machine = Machine::new(&program);
+        machine.run(&mut &b""[..], &mut Vec::new()).unwrap();
+        render_grid(&machine).unwrap()
+    }
+
+    // Each cell is drawn as a 16x16 square
+    fn expected_pixel(x: usize, y: usize) -> [u8; 3] {
+        match (x / 16, y / 16) {
+            (0, 0) => [100, 100, 100],
+            (1, 0) => [0, 0, 0],
+            (0, 1) => BACKGROUND,
+            _ => POINTER
+        }
+    }
+
+    // Table-driven, unlike `png_chunk`
+    fn crc32(bytes: &[u8]) -> u32 {
+        let table: Vec<u32> = (0..256u32).map(|n| {
+            (0..8).fold(n, |c, _| if c & 1 == 1 { 0xEDB88320 ^ (c >> 1) } else { c >> 1 })
+        }).collect();
+
+        !bytes.iter().fold(!0u32, |crc, byte| table[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8))
+    }
+
+    #[test]
+    fn ppm_header_and_body() {
+        let ppm = to_ppm(&two_by_two());
+        let header = b"P6\n32 32\n255\n";
+
+        assert_eq!(&ppm[..header.len()], header);
+        assert_eq!(ppm.len(), header.len() + 32 * 32 * 3);
+
+        for (index, pixel) in ppm[header.len()..].chunks(3).enumerate() {
+            assert_eq!(pixel, expected_pixel(index % 32, index / 32), "pixel {}", index);
+        }
+    }
+
+    #[test]
+    fn png_signature_header_and_checksums() {
+        let png = to_png(&two_by_two());
+
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+
+        // IHDR: 32x32, 8 bits per channel, RGB, no interlacing
+        assert_eq!(&png[8..16], b"\x00\x00\x00\x0dIHDR");
+        assert_eq!(&png[16..29], [0, 0, 0, 32, 0, 0, 0, 32, 8, 2, 0, 0, 0]);
+        assert_eq!(png[29..33], crc32(&png[12..29]).to_be_bytes());
+
+        let length = u32::from_be_bytes([png[33], png[34], png[35], png[36]]) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        let idat = &png[41..41 + length];
+        assert_eq!(png[41 + length..45 + length], crc32(&png[37..41 + length]).to_be_bytes());
+
+        // The zlib stream ends with the Adler-32 of the rows, each starting
+        // with filter type 0
+        let raw: Vec<u8> = (0..32).flat_map(|y| {
+            std::iter::once(0).chain((0..32).flat_map(move |x| expected_pixel(x, y).to_vec()))
+        }).collect();
+        let (a, b) = raw.iter().fold((1u32, 0u32), |(a, b), byte| {
+            let a = (a + *byte as u32) % 65521;
+            (a, (b + a) % 65521)
+        });
+        assert_eq!(idat[..2], [0x78, 0x01]);
+        assert_eq!(idat[idat.len() - 4..], ((b << 16) | a).to_be_bytes());
+
+        assert_eq!(&png[45 + length..], b"\x00\x00\x00\x00IEND\xAE\x42\x60\x82");
+    }
+
+    #[test]
+    fn records_a_frame_per_write_and_the_end() {
+        let dir = temp_dir("record-gif");
+        let path = dir.join("out.gif");
+
+        assert_eq!(record("65 . 1 write 1 r 66 . 1 write 1 r 67 .", None, &path), 2);
+        assert_eq!(gif_frames(&fs::read(&path).unwrap()), 3);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn records_every_n_steps_as_numbered_images() {
+        let dir = temp_dir("record-ppm");
+        let path = dir.join("out.ppm");
+
+        // Seven steps, so frames after the third and sixth
+        assert_eq!(record("1 . 1 r 2 . 1 r", Some(3), &path), 2);
+
+        let images: Vec<Vec<u8>> = (1..=3).map(|n| fs::read(dir.join(format!("out-{:04}.ppm", n))).unwrap()).collect();
+        assert!(!dir.join("out-0004.ppm").exists());
+        // Every frame covers the three cells the whole recording touched
+        assert!(images.iter().all(|image| image.starts_with(b"P6\n48 16\n255\n")));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,256 @@
+use super::{ Direction, Error, FileId, TokenPos };
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexemeKind {
+    // Unsigned integer literal, e.g. `42`
+    Int(u64),
+    // Any other whitespace-delimited word, e.g. `dup` or `+`
+    Word(String),
+    // Preprocessor directive without the leading `#`, e.g. `const`
+    Directive(String),
+    // String literal with escapes resolved, e.g. `"hi\n"` or `"hi"d`
+    Str(Vec<u8>, Direction),
+}
+
+#[derive(Debug, Clone)]
+pub struct Lexeme {
+    pub kind: LexemeKind,
+    pub pos: TokenPos
+}
+
+struct Cursor<'a> {
+    src: &'a str,
+    file: FileId,
+    offset: usize,
+    line: usize,
+    col: usize
+}
+
+impl<'a> Cursor<'a> {
+    fn peek(&self) -> Option<char> {
+        self.src[self.offset..].chars().next()
+    }
+
+    fn peek_second(&self) -> Option<char> {
+        self.src[self.offset..].chars().nth(1)
+    }
+
+    // `//` starts a comment anywhere outside a string literal
+    fn at_comment(&self) -> bool {
+        self.peek() == Some('/') && self.peek_second() == Some('/')
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.offset += c.len_utf8();
+
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+
+        Some(c)
+    }
+
+    fn pos(&self) -> TokenPos {
+        TokenPos {
+            file: self.file,
+            offset: self.offset,
+            len: 0,
+            line: self.line,
+            col: self.col
+        }
+    }
+}
+
+// Malformed lexemes are pushed onto `errors` and skipped, so the parser can
+// still report problems in the rest of the file.
+pub fn tokenize(file: FileId, src: &str, errors: &mut Vec<Error>) -> Vec<Lexeme> {
+    let mut cursor = Cursor { src, file, offset: 0, line: 1, col: 1 };
+    let mut lexemes: Vec<Lexeme> = Vec::new();
+
+    while let Some(c) = cursor.peek() {
+        if c.is_whitespace() {
+            cursor.bump();
+            continue;
+        }
+
+        // Line comments run until the end of the line
+        if cursor.at_comment() {
+            while let Some(c) = cursor.peek() {
+                if c == '\n' {
+                    break;
+                }
+                cursor.bump();
+            }
+            continue;
+        }
+
+        if c == '"' {
+            match lex_string(&mut cursor) {
+                Ok(lexeme) => lexemes.push(lexeme),
+                Err(err) => {
+                    errors.push(err);
+
+                    // Skip the rest of the malformed literal
+                    while let Some(c) = cursor.peek() {
+                        if c.is_whitespace() {
+                            break;
+                        }
+                        cursor.bump();
+                    }
+                }
+            }
+            continue;
+        }
+
+        let mut pos = cursor.pos();
+        while let Some(c) = cursor.peek() {
+            if c.is_whitespace() || cursor.at_comment() {
+                break;
+            }
+            cursor.bump();
+        }
+        pos.len = cursor.offset - pos.offset;
+
+        let text = &src[pos.offset..cursor.offset];
+        let kind = if let Some(name) = text.strip_prefix('#') {
+            if name.is_empty() {
+                errors.push(Error::new("Expected a directive name after `#`.", pos));
+                continue;
+            }
+
+            LexemeKind::Directive(name.to_string())
+        } else if text.chars().all(|c| c.is_ascii_digit()) {
+            if let Ok(num) = text.parse::<u64>() {
+                LexemeKind::Int(num)
+            } else {
+                errors.push(
+                    Error::new(format!("Invalid number {}.", text), pos)
+                        .with_note(format!("Numbers must be at most {}.", u64::MAX))
+                );
+                continue;
+            }
+        } else {
+            LexemeKind::Word(text.to_string())
+        };
+
+        lexemes.push(Lexeme { kind, pos });
+    }
+
+    lexemes
+}
+
+// Lexes `"..."` with an optional direction suffix (`r`, `l`, `u` or `d`,
+// defaulting to right) directly after the closing quote.
+fn lex_string(cursor: &mut Cursor) -> Result<Lexeme, Error> {
+    let mut pos = cursor.pos();
+    let mut bytes: Vec<u8> = Vec::new();
+
+    cursor.bump();
+
+    loop {
+        let escape_pos = cursor.pos();
+
+        match cursor.bump() {
+            Some('"') => break,
+            Some('\\') => {
+                let byte = match cursor.bump() {
+                    Some('n') => b'\n',
+                    Some('t') => b'\t',
+                    Some('r') => b'\r',
+                    Some('0') => b'\0',
+                    Some('\\') => b'\\',
+                    Some('"') => b'"',
+                    c => {
+                        let mut escape_pos = escape_pos;
+                        escape_pos.len = cursor.offset - escape_pos.offset;
+
+                        return Err(
+                            Error::new(format!("Unknown escape sequence `\\{}` in string literal.", c.map(String::from).unwrap_or_default()), escape_pos)
+                                .with_note("Supported escapes are \\n, \\t, \\r, \\0, \\\\ and \\\".")
+                        );
+                    }
+                };
+                bytes.push(byte);
+            },
+            Some('\n') | None => {
+                pos.len = 1;
+                return Err(
+                    Error::new("Unterminated string literal.", pos)
+                        .with_help("Close the string with `\"` before the end of the line.")
+                );
+            },
+            Some(c) => {
+                let mut buf = [0; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            }
+        }
+    }
+
+    let direction = match cursor.peek() {
+        Some('r') => Some(Direction::Right),
+        Some('l') => Some(Direction::Left),
+        Some('u') => Some(Direction::Up),
+        Some('d') => Some(Direction::Down),
+        _ => None
+    };
+    if direction.is_some() {
+        cursor.bump();
+    }
+
+    if let Some(c) = cursor.peek() {
+        if !c.is_whitespace() && !cursor.at_comment() {
+            let mut suffix_pos = cursor.pos();
+            suffix_pos.len = c.len_utf8();
+
+            return Err(
+                Error::new("Unexpected character after string literal.", suffix_pos)
+                    .with_help("A string literal may only be followed by a direction: `r`, `l`, `u` or `d`.")
+            );
+        }
+    }
+
+    pos.len = cursor.offset - pos.offset;
+
+    Ok(Lexeme { kind: LexemeKind::Str(bytes, direction.unwrap_or(Direction::Right)), pos })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(src: &str) -> Vec<LexemeKind> {
+        let mut errors: Vec<Error> = Vec::new();
+        let lexemes = tokenize(0, src, &mut errors);
+        assert!(errors.is_empty(), "unexpected errors: {:?}", errors);
+
+        lexemes.into_iter().map(|lexeme| lexeme.kind).collect()
+    }
+
+    #[test]
+    fn comment_ends_a_word() {
+        assert_eq!(kinds("5 print// note\n6"), vec![
+            LexemeKind::Int(5),
+            LexemeKind::Word("print".into()),
+            LexemeKind::Int(6),
+        ]);
+    }
+
+    #[test]
+    fn comment_after_string_literal() {
+        assert_eq!(kinds("\"a//b\"d// note"), vec![LexemeKind::Str(b"a//b".to_vec(), Direction::Down)]);
+    }
+
+    #[test]
+    fn single_slash_is_a_word() {
+        assert_eq!(kinds("6 3 / 4/2"), vec![
+            LexemeKind::Int(6),
+            LexemeKind::Int(3),
+            LexemeKind::Word("/".into()),
+            LexemeKind::Word("4/2".into()),
+        ]);
+    }
+}
@@ -1,82 +1,159 @@
-use std::collections::{ HashMap, HashSet };
-
+pub mod check;
 pub mod com;
+pub mod debug;
+pub mod diag;
+pub mod image;
+pub mod lex;
+pub mod parse;
 pub mod sim;
+pub mod snapshot;
+pub mod tui;
+
+// How deeply function calls may nest. The compiled program keeps return
+// addresses in a fixed-size array, and the simulator enforces the same limit
+pub const MAX_CALL_DEPTH: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    // Problems found before the program runs (I/O, lexing, parsing, ...)
+    Diagnostic(Diagnostic),
+    // Raised by the simulator while executing the program
+    RuntimeError(RuntimeError),
+    // An external program run by the compiler (assembler, linker) failed
+    ToolError(ToolError),
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub msg: String,
+    // `None` for errors that aren't tied to a location in the source
+    pub pos: Option<TokenPos>,
+    pub notes: Vec<String>,
+    pub help: Option<String>
+}
+
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    pub msg: String,
+    // The token that failed to execute
+    pub pos: TokenPos,
+    // Machine state at the time of the error, bottom of the stack first
+    pub stack: Vec<u64>,
+    pub mem_addr: (u32, u32)
+}
 
-fn byte_slice_to_fixed_len<const N: usize>(s: &[u8], fill: u8) -> [u8; N] {
-    assert!(s.len() <= N);
+#[derive(Debug, Clone)]
+pub struct ToolError {
+    pub msg: String,
+    // What the compiler was doing, e.g. "assemble" or "link"
+    pub stage: String,
+    // The command line that was run
+    pub command: String,
+    // `None` if the program could not be started or was killed by a signal
+    pub status: Option<i32>,
+    pub stderr: String
+}
 
-    let mut a = [fill; N];
+impl Error {
+    pub fn new(msg: impl Into<String>, pos: TokenPos) -> Self {
+        Error::Diagnostic(Diagnostic { msg: msg.into(), pos: Some(pos), notes: Vec::new(), help: None })
+    }
 
-    for (i, elem) in s.iter().enumerate() {
-        a[i] = elem.clone();
+    pub fn without_pos(msg: impl Into<String>) -> Self {
+        Error::Diagnostic(Diagnostic { msg: msg.into(), pos: None, notes: Vec::new(), help: None })
     }
 
-    a
-}
+    pub fn runtime(msg: impl Into<String>, pos: TokenPos, stack: &[u64], mem_addr: (u32, u32)) -> Self {
+        Error::RuntimeError(RuntimeError { msg: msg.into(), pos, stack: stack.to_vec(), mem_addr })
+    }
 
-#[derive(Debug)]
-pub struct Error {
-    msg: String,
-    pos: TokenPos
+    pub fn tool(msg: impl Into<String>, stage: &str, command: String, status: Option<i32>, stderr: String) -> Self {
+        Error::ToolError(ToolError { msg: msg.into(), stage: stage.to_string(), command, status, stderr })
+    }
+
+    // Notes and help only apply to diagnostics; runtime and tool errors carry
+    // their own context instead.
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        if let Error::Diagnostic(diagnostic) = &mut self {
+            diagnostic.notes.push(note.into());
+        }
+        self
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        if let Error::Diagnostic(diagnostic) = &mut self {
+            diagnostic.help = Some(help.into());
+        }
+        self
+    }
+
+    pub fn msg(&self) -> &str {
+        match self {
+            Error::Diagnostic(diagnostic) => &diagnostic.msg,
+            Error::RuntimeError(err) => &err.msg,
+            Error::ToolError(err) => &err.msg
+        }
+    }
+
+    pub fn pos(&self) -> Option<TokenPos> {
+        match self {
+            Error::Diagnostic(diagnostic) => diagnostic.pos,
+            Error::RuntimeError(err) => Some(err.pos),
+            Error::ToolError(_) => None
+        }
+    }
 }
+
 impl std::error::Error for Error { }
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}: ERROR: {}", self.pos, self.msg)
+        match self {
+            Error::Diagnostic(Diagnostic { msg, pos: Some(pos), .. }) => write!(f, "{}: ERROR: {}", pos, msg),
+            Error::Diagnostic(Diagnostic { msg, pos: None, .. }) => write!(f, "ERROR: {}", msg),
+            Error::RuntimeError(err) => write!(f, "{}: RUNTIME ERROR: {}", err.pos, err.msg),
+            Error::ToolError(err) => write!(f, "ERROR: {}", err.msg)
+        }
     }
 }
 
-pub fn load_file(filepath: &str) -> Result<Vec<String>, Error> {
-    use std::fs;
+pub type FileId = usize;
 
-    if let Ok(file) = fs::read_to_string(filepath) {
-        let mut lines: Vec<String> = file.lines()
-           .map(|l| 
-                l.split("//").find(|_| true).unwrap().to_string()
-            ).collect();
-
-        let mut const_dict: HashMap<String, usize> = HashMap::new();
-        let const_declarations: Vec<String> = lines.iter().filter(|l| l.starts_with("#const")).cloned().collect();
-        lines.retain(|l| !l.starts_with("#const"));
-
-        if !const_declarations.is_empty() {
-            for decl in const_declarations {
-                if let [_, name, val] = decl.split(' ').collect::<Vec<&str>>().as_slice() {
-                    const_dict.insert(name.to_string(), val.parse().expect("Unable to parse constant value."));
-                } else {
-                    return Err(Error { 
-                        msg: format!("Unable to parse const declaration: '{}'", decl), 
-                        pos: TokenPos::default() 
-                    });
-                }
-            }
-
-            for line in lines.iter_mut() {
-                let line_clone = line.clone();
-                let words: Vec<&str> = line_clone.split(' ').collect();
-
-                for word in words {
-                    if const_dict.contains_key(word.trim()) {
-                        *line = line.replace(word, &const_dict.get(word.trim()).unwrap().to_string());
-                    }
-                }
-            }
-        }
+#[derive(Debug)]
+pub struct SourceFile {
+    pub name: String,
+    pub text: String
+}
 
-        Ok(lines)
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>
+}
+
+impl SourceMap {
+    pub fn add(&mut self, name: &str, text: String) -> FileId {
+        self.files.push(SourceFile { name: name.to_string(), text });
+        self.files.len() - 1
+    }
+
+    pub fn get(&self, file: FileId) -> &SourceFile {
+        &self.files[file]
+    }
+}
+
+pub fn load_file(sources: &mut SourceMap, filepath: &str) -> Result<FileId, Error> {
+    use std::fs;
+
+    if let Ok(text) = fs::read_to_string(filepath) {
+        Ok(sources.add(filepath, text))
     } else {
-        Err(Error { 
-            msg: format!("Unable to open file: {}", filepath), 
-            pos: TokenPos::default() 
-        })
+        Err(Error::without_pos(format!("Unable to open file: {}", filepath)))
     }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub enum Token {
     // For pushing a number to the stack
-    Num(usize),
+    Num(u64),
 
     // Mathematical operations
     OpAdd,
@@ -87,6 +164,15 @@ pub enum Token {
     // Stdout interaction
     Print,
     Write,
+    // Raw bytes: `emit` pops a value, `puts` pops a number of cells to write
+    Emit,
+    Puts,
+
+    // Stdin interaction: `key` pushes a byte (or -1 at end of input),
+    // `readln` stores a line in the cells right of the pointer and pushes
+    // its length
+    Key,
+    ReadLine,
     
     // Stack operations
     Dup,
@@ -94,13 +180,6 @@ pub enum Token {
     Swap,
     Over,
 
-    // Conditionals and Loops
-    If(usize),
-    Else(usize),
-    While,
-    Do(usize),
-    End(isize),
-
     // Conditional operators
     Eq,
     GT,
@@ -119,9 +198,78 @@ pub enum Token {
     Load,
     Copy,
 
-    // Functions
-    Fn([u8; 256], usize),
-    FnCall([u8; 256]),
+    // Lays a string literal (index into `parse::Program::strings`) out in
+    // the grid from the current cell and pushes its length
+    Str(usize, Direction),
+
+    // Functions (index into `parse::Program::functions`)
+    FnCall(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Direction::Up => write!(f, "up"),
+            Direction::Down => write!(f, "down"),
+            Direction::Left => write!(f, "left"),
+            Direction::Right => write!(f, "right"),
+        }
+    }
+}
+
+// Size of a grid cell. Values are truncated to it when stored and read back
+// zero-extended.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum CellWidth {
+    #[default]
+    U8,
+    U16,
+    U32,
+    U64,
+}
+impl CellWidth {
+    pub fn bytes(&self) -> u8 {
+        match self {
+            CellWidth::U8 => 1,
+            CellWidth::U16 => 2,
+            CellWidth::U32 => 4,
+            CellWidth::U64 => 8,
+        }
+    }
+
+    pub fn truncate(&self, value: u64) -> u64 {
+        match self {
+            CellWidth::U8 => value as u8 as u64,
+            CellWidth::U16 => value as u16 as u64,
+            CellWidth::U32 => value as u32 as u64,
+            CellWidth::U64 => value,
+        }
+    }
+}
+impl std::str::FromStr for CellWidth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "u8" => Ok(CellWidth::U8),
+            "u16" => Ok(CellWidth::U16),
+            "u32" => Ok(CellWidth::U32),
+            "u64" => Ok(CellWidth::U64),
+            _ => Err(format!("Unknown cell width `{}`.", s))
+        }
+    }
+}
+impl std::fmt::Display for CellWidth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "u{}", self.bytes() as u32 * 8)
+    }
 }
 
 impl Token {
@@ -134,15 +282,14 @@ impl Token {
             Token::OpDiv => "; -- div --",
             Token::Print => "; -- print --",
             Token::Write => "; -- write --",
+            Token::Emit => "; -- emit --",
+            Token::Puts => "; -- puts --",
+            Token::Key => "; -- key --",
+            Token::ReadLine => "; -- readln --",
             Token::Dup => "; -- dup --",
             Token::Drop => "; -- drop --",
             Token::Swap => "; -- swap --",
             Token::Over => "; -- over --",
-            Token::If(_) => "; -- if --",
-            Token::Else(_) => "; -- else --",
-            Token::While => "; -- while --",
-            Token::Do(_) => "; -- do --",
-            Token::End(_) => "; -- end --",
             Token::Eq => "; -- eq --",
             Token::GT => "; -- gt --",
             Token::LT => "; -- lt --",
@@ -157,7 +304,7 @@ impl Token {
             Token::Store => "; -- store --",
             Token::Load => "; -- load --",
             Token::Copy => "; -- copy --",
-            Token::Fn(..) => "; -- fn --",
+            Token::Str(..) => "; -- string --",
             Token::FnCall(_) => "; -- fn call --",
         }.into()
     }
@@ -165,252 +312,31 @@ impl Token {
 
 #[derive(Debug, Default, Clone, Copy)]
 pub struct TokenPos {
-    pub row: usize,
-    pub col: usize,
-    pub ip: usize
+    pub file: FileId,
+    // Byte offset and length of the token within its file
+    pub offset: usize,
+    pub len: usize,
+    // 1-based line and column (in characters)
+    pub line: usize,
+    pub col: usize
 }
 impl std::fmt::Display for TokenPos {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}:{}", self.row, self.col)
+        write!(f, "{}:{}", self.line, self.col)
     }
 }
 
-pub fn get_block_end(tokens: &[(Token, TokenPos)], block_start: TokenPos) -> Result<usize, Error> {
-    let mut ip = block_start.ip + 1;
-    let mut block_depth = 1;
-
-    while ip < tokens.len() {
-        let (token, _) = &tokens[ip];
-        match token {
-            Token::If(_) | Token::Do(_) => block_depth += 1,
-            Token::End(_) => block_depth -= 1,
-            _ => { }
-        }
-
-        if block_depth == 0 {
-            return Ok(ip);
-        }
-
-        ip += 1;
-    }
-
-    Err(Error { msg: "Block is not terminated with the `end` keyword.".into(), pos: block_start })
+// Adds `src` as a file named `test.lat`, for the unit tests
+#[cfg(test)]
+pub(crate) fn test_sources(src: &str) -> (SourceMap, FileId) {
+    let mut sources = SourceMap::default();
+    let file = sources.add("test.lat", src.to_string());
+    (sources, file)
 }
 
-pub fn get_if_next_ip(tokens: &[(Token, TokenPos)], if_start: TokenPos) -> Result<usize, Error> {
-    let mut ip = if_start.ip + 1;
-    let mut block_depth = 1;
-
-    while ip < tokens.len() {
-        let (token, _) = &tokens[ip];
-        match token {
-            Token::If(_) | Token::Do(_) => block_depth += 1,
-            Token::Else(_) => {
-                if block_depth == 1 {
-                    return Ok(ip);
-                }            
-            },
-            Token::End(_) => block_depth -= 1,
-            _ => { }
-        }
-
-        if block_depth == 0 {
-            return Ok(ip)
-        }
-
-        ip += 1;
-    }
-
-    Err(Error { msg: "If statement is not terminated with the `end` keyword".into(), pos: if_start })
-}
-
-pub struct LexerOutput {
-    pub tokens: Vec<(Token, TokenPos)>,
-    pub fn_tokens: Vec<(Token, TokenPos)>,
-}
-
-pub fn lex_lines(lines: Vec<String>) -> Result<LexerOutput, Error> {
-    let mut tokens: Vec<(Token, TokenPos)> = Vec::new();
-    let mut fn_tokens: Vec<(Token, TokenPos)> = Vec::new();
-    let mut ip: usize = 0;
-
-    let mut blocks: Vec<(Token, TokenPos)> = Vec::new();
-    let mut terminated_blocks: Vec<(Token, TokenPos)> = Vec::new();
-
-    let mut functions: HashSet<&[u8]> = HashSet::new();
-    let mut inside_fn: bool = false;
-
-    for (row, line) in lines.iter().enumerate() {
-        let ts: Vec<&str> = line.split_ascii_whitespace().collect();
-
-        let mut prev_fn = false;
-
-        for (col, t) in ts.iter().cloned().enumerate() {
-            let pos = TokenPos { 
-                row,
-                col,
-                ip
-            };
-
-            let token = match t {
-                t if t.chars().all(|c| c.is_ascii_digit()) => {
-                    if let Ok(num) = t.parse::<usize>() {
-                        Token::Num(num)
-                    } else {
-                        return Err(Error {
-                            msg: format!("Invalid number {}.", t),
-                            pos
-                        });
-                    }
-                },
-                "+" => Token::OpAdd,
-                "-" => Token::OpSub,
-                "*" => Token::OpMul,
-                "/" => Token::OpDiv,
-                "=" => Token::Eq,
-                ">" => Token::GT,
-                "<" => Token::LT,
-                "and" => Token::And,
-                "not" => Token::Not,
-                "or" => Token::Or,
-                "print" => Token::Print,
-                "write" => Token::Write,
-                "dup" => Token::Dup,
-                "drop" => Token::Drop,
-                "swap" => Token::Swap,
-                "over" => Token::Over,
-                "u" => Token::Up,
-                "d" => Token::Down,
-                "l" => Token::Left,
-                "r" => Token::Right,
-                "loc" => Token::Loc,
-                "." => Token::Store,
-                "," => Token::Load,
-                "?" => Token::Copy,
-                "if" => { 
-                    let t = Token::If(0);
-                    blocks.push((t, pos));
-                    terminated_blocks.push((t, pos));
-                    t
-                },
-                "else" => {
-                    let t = Token::Else(0);
-                    if let (Token::If(_), _) = terminated_blocks.last().unwrap_or(&(Token::Not, TokenPos::default())) {
-                        terminated_blocks.pop();
-                    }
-                    blocks.push((t, pos));
-                    terminated_blocks.push((t, pos));
-                    t
-                },
-                "while" => { 
-                    let t = Token::While;
-                    blocks.push((t, pos));
-                    terminated_blocks.push((t, pos));
-                    t
-                },
-                "do" => { 
-                    let t = Token::Do(0);
-                    blocks.push((t, pos));
-                    t
-                },
-                "end" => {
-                    let (block_type, block_start) = terminated_blocks.pop().unwrap();
-                    if let Token::If(_) = block_type {
-                        Token::End(ip as isize)
-                    } else if let Token::Else(_) = block_type { 
-                        Token::End(ip as isize)
-                    } else if let Token::While = block_type { 
-                        Token::End(block_start.ip as isize)
-                    } else if let Token::Fn(..) = block_type { 
-                        inside_fn = false;
-                        Token::End(-1)
-                    } else {
-                        Token::End(block_start.ip as isize) 
-                    }
-                },
-                "fn" => {
-                    let t = Token::Fn([0; 256], 0);
-
-                    prev_fn = true;
-
-                    t
-                },
-                t if prev_fn => {
-                    let (_, pos) = tokens.pop().unwrap();
-                    let mut fn_name = [0; 256];
-                    let chars = t.as_bytes();
-
-                    assert!(chars.len() <= 256);
-
-                    for (i, c) in chars.into_iter().enumerate() {
-                        fn_name[i] = *c;
-                    }
-
-                    prev_fn = false;
-                    functions.insert(t.as_bytes());
-                    inside_fn = true;
-
-                    let t = Token::Fn(fn_name, 0);
-                    terminated_blocks.push((t, pos));
-
-                    ip -= 1;
-
-                    t
-                },
-                t if functions.contains(t.as_bytes()) => {
-                    if let Some(fn_name) = functions.get(t.as_bytes()) {
-                        Token::FnCall(byte_slice_to_fixed_len(fn_name, 0))
-                    } else {
-                        panic!("Function name not found")
-                    }
-                },
-                _ => { 
-                    return Err(Error {
-                        msg: format!("Unimplemented token {}", t),
-                        pos
-                    })
-                },
-            };
-
-            if inside_fn {
-                fn_tokens.push((token, pos));
-            } else if let Token::End(-1) = token {
-                fn_tokens.push((token, pos));
-            } else {
-                tokens.push((token, pos));
-            }
-            ip += 1;
-        }
-    }
-
-    for (b, block_start) in &blocks {
-        if let (Token::If(_), ip) = tokens[block_start.ip] {
-            let next = get_if_next_ip(tokens.as_slice(), *block_start)?;
-            
-            tokens[block_start.ip] = (Token::If(next), ip);
-        } else if let (Token::Else(_), ip) = tokens[block_start.ip] { 
-            let next = get_block_end(tokens.as_slice(), *block_start)?;
-
-            tokens[block_start.ip] = (Token::Else(next), ip);
-        } else if let (Token::Do(_), ip) = tokens[block_start.ip] {
-            let end_ip = get_block_end(tokens.as_slice(), *block_start)?;
-
-            tokens[block_start.ip] = (Token::Do(end_ip), ip);
-        } else if let (Token::While, _) = tokens[block_start.ip] { 
-        } else if let (Token::Fn(..), _) = (b, block_start) {
-        } else {
-            println!("BLOCKS: {:?} \n B {:?} \n BLOCK START {:?}", &blocks, b, tokens[block_start.ip]);
-            todo!("Implement missing block logic.")
-        }
-    }
-    
-    // #[cfg(debug_assertions)]
-    // for (token, pos) in &tokens {
-    //     println!("{:?}: {}", token, pos.ip);
-    // }
-
-    Ok(LexerOutput {
-        tokens,
-        fn_tokens
-    })
+#[cfg(test)]
+pub(crate) fn test_program(src: &str) -> (SourceMap, parse::Program) {
+    let (sources, file) = test_sources(src);
+    let program = parse::parse_file(&sources, file).unwrap();
+    (sources, program)
 }
@@ -1,14 +1,63 @@
-use clap::{ Arg, App, AppSettings, SubCommand };
+use std::fs;
+use std::io;
+use std::path::{ Path, PathBuf };
+
+use clap::{ Arg, App, AppSettings, ArgMatches, SubCommand };
 
 use lattice_lib::*;
+use lattice_lib::diag::ErrorFormat;
+use lattice_lib::snapshot::{ self, Snapshot };
 
-fn main() -> Result<(), Error> {
+fn main() {
     let matches = App::new("Lattice Programming Language")
         .version("v0.1")
         .about("A stack-based language with a cell-based memory structure.")
         .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(Arg::with_name("error-format")
+            .long("error-format")
+            .help("How to report errors")
+            .takes_value(true)
+            .possible_values(&["human", "json"])
+            .default_value("human")
+            .global(true)
+        )
         .subcommand(SubCommand::with_name("sim")
             .about("Simulate the program without compiling.")
+            .arg(Arg::with_name("trace")
+                 .long("trace")
+                 .help("Log every executed token, the stack before and after it and the grid pointer to stderr")
+                 .takes_value(true)
+                 .value_name("FORMAT")
+                 .min_values(0)
+                 .require_equals(true)
+                 .possible_values(&["human", "json"])
+            )
+            .arg(Arg::with_name("dump-grid")
+                 .long("dump-grid")
+                 .help("Draw the touched cells of the grid to a .ppm, .png or .gif image when the program ends")
+                 .takes_value(true)
+                 .value_name("PATH")
+            )
+            .arg(Arg::with_name("tui")
+                 .long("tui")
+                 .help("Show the grid, stack and source line in the terminal while stepping through the program. \
+                        Programs that use `key` or `readln` must have their input piped in")
+                 .conflicts_with_all(&["trace", "record"])
+            )
+            .arg(Arg::with_name("record")
+                 .long("record")
+                 .help("Record the grid as it changes to an animated .gif, or to numbered .png or .ppm images")
+                 .takes_value(true)
+                 .value_name("PATH")
+            )
+            .arg(Arg::with_name("record-every")
+                 .long("record-every")
+                 .help("Record a frame every N steps instead of after each `write`")
+                 .takes_value(true)
+                 .value_name("N")
+                 .requires("record")
+            )
+            .arg(cell_width_arg())
             .arg(Arg::from_usage("[FILE]")
                 .required(true)
             )
@@ -19,26 +68,256 @@ fn main() -> Result<(), Error> {
                  .short("r")
                  .help("run after compiling")
             )
+            .arg(Arg::with_name("nasm")
+                 .long("nasm")
+                 .help("Assemble and link with nasm and ld instead of the built-in assembler")
+            )
+            .arg(Arg::with_name("keep-temps")
+                 .long("keep-temps")
+                 .help("Keep intermediate files (.asm, .o) next to the output")
+            )
+            .arg(Arg::with_name("output")
+                 .short("o")
+                 .help("Where to write the output (defaults to FILE without .lat)")
+                 .takes_value(true)
+                 .value_name("PATH")
+            )
+            .arg(Arg::with_name("emit")
+                 .long("emit")
+                 .help("Stop after producing assembly, an object file or an executable")
+                 .takes_value(true)
+                 .possible_values(&["asm", "obj", "exe"])
+                 .default_value("exe")
+            )
+            .arg(cell_width_arg())
+            .arg(Arg::from_usage("[FILE]")
+                .required(true)
+            )
+            .arg(Arg::with_name("ARGS")
+                .help("Arguments passed to the program when running it with -r")
+                .multiple(true)
+                .last(true)
+            )
+        )
+        .subcommand(SubCommand::with_name("debug")
+            .about("Step through the program in the simulator, reading commands from stdin.")
+            .arg(cell_width_arg())
             .arg(Arg::from_usage("[FILE]")
                 .required(true)
             )
+        )
+        .subcommand(SubCommand::with_name("test")
+            .about("Run programs with sim and compare them against their .expected files.")
+            .arg(Arg::with_name("bless")
+                 .long("bless")
+                 .help("Record the current output as expected instead of comparing")
+            )
+            .arg(Arg::with_name("PATH")
+                .help("Programs or directories of programs to test [default: tests examples]")
+                .multiple(true)
+            )
         ).get_matches();
 
+    let mut sources = SourceMap::default();
+
+    match run(&matches, &mut sources) {
+        Ok(0) => { },
+        Ok(code) => std::process::exit(code),
+        Err(errors) => {
+            let (_, sub_matches) = matches.subcommand();
+            let format: ErrorFormat = sub_matches
+                .and_then(|m| m.value_of("error-format"))
+                .unwrap_or("human")
+                .parse()
+                .unwrap_or(ErrorFormat::Human);
+
+            diag::report(&sources, &errors, format);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn cell_width_arg() -> Arg<'static, 'static> {
+    Arg::with_name("cell-width")
+        .long("cell-width")
+        .help("Size of a grid cell, overriding the program's #cellwidth [default: u8]")
+        .takes_value(true)
+        .possible_values(&["u8", "u16", "u32", "u64"])
+}
+
+// Parses and checks FILE, applying `--cell-width`
+fn load_program(matches: &ArgMatches, sources: &mut SourceMap) -> Result<parse::Program, Vec<Error>> {
+    let file = matches.value_of("FILE").unwrap();
+    let file_id = load_file(sources, file).map_err(|e| vec![e])?;
+    let mut program = parse::parse_file(sources, file_id)?;
+    check::check_program(&program)?;
+
+    if let Some(width) = matches.value_of("cell-width") {
+        program.cell_width = width.parse().map_err(|e| vec![Error::without_pos(e)])?;
+    }
+
+    Ok(program)
+}
+
+// Returns the exit code for lattice itself
+fn run(matches: &ArgMatches, sources: &mut SourceMap) -> Result<i32, Vec<Error>> {
     if let Some(matches) = matches.subcommand_matches("com") {
         let file = matches.value_of("FILE").unwrap();
-        let lines = load_file(file)?;
-        let tokens = lex_lines(lines)?;
-        
-        com::compile(&tokens, &file)?;
+        let program = load_program(matches, sources)?;
+
+        let options = com::CompileOptions {
+            use_nasm: matches.is_present("nasm"),
+            keep_temps: matches.is_present("keep-temps"),
+            emit: matches.value_of("emit").unwrap().parse().map_err(|e| vec![e])?,
+            output: matches.value_of("output").map(PathBuf::from)
+        };
+
+        if matches.is_present("run") && options.emit != com::Emit::Exe {
+            return Err(vec![Error::without_pos("`-r` needs an executable to run; it can't be combined with `--emit asm` or `--emit obj`.")]);
+        }
+
+        let executable = com::compile(&program, file, &options).map_err(|e| vec![e])?;
+
+        if matches.is_present("run") {
+            let args: Vec<&str> = matches.values_of("ARGS").map_or(Vec::new(), |args| args.collect());
+
+            return com::run_executable(&executable, &args).map_err(|e| vec![e]);
+        }
     } else if let Some(matches) = matches.subcommand_matches("sim") {
-        let file = matches.value_of("FILE").unwrap();
-        let lines = load_file(file)?;
-        let tokens = lex_lines(lines)?;
-        
-        sim::simulate(&tokens.tokens)?;
+        let program = load_program(matches, sources)?;
+
+        let dump_grid = matches.value_of("dump-grid").map(Path::new);
+        let record = matches.value_of("record").map(Path::new);
+        for path in dump_grid.iter().chain(record.iter()) {
+            image::ImageFormat::from_path(path).map_err(|e| vec![e])?;
+        }
+
+        let trace_format: Option<sim::TraceFormat> = match matches.is_present("trace") {
+            true => Some(matches.value_of("trace").unwrap_or("human").parse().map_err(|e| vec![e])?),
+            false => None
+        };
+        let mut recorder = match matches.value_of("record-every") {
+            Some(every) => match every.parse::<u64>() {
+                Ok(every) if every > 0 => Some(image::Recorder::new(Some(every))),
+                _ => return Err(vec![Error::without_pos(format!("`--record-every` takes a number of steps, not `{}`.", every))])
+            },
+            None => record.map(|_| image::Recorder::new(None))
+        };
+
+        let mut machine = sim::Machine::new(&program);
+        let (mut stdin, mut stdout) = (io::stdin().lock(), io::stdout().lock());
+
+        let result = if matches.is_present("tui") {
+            tui::check_input(&program).map_err(|e| vec![e])?;
+            tui::run(&mut machine, sources, matches.value_of("FILE").unwrap(), &mut stdin, &mut stdout)
+        } else if trace_format.is_some() || recorder.is_some() {
+            let mut trace = io::BufWriter::new(io::stderr().lock());
+
+            machine.run_with(&mut stdin, &mut stdout, &mut |machine, step| {
+                if let Some(format) = trace_format {
+                    sim::write_trace(&mut trace, sources, format, machine, step);
+                }
+                match &mut recorder {
+                    Some(recorder) => recorder.observe(machine, step),
+                    None => Ok(())
+                }
+            })
+        } else {
+            machine.run(&mut stdin, &mut stdout)
+        };
+
+        // The grid is drawn even if the program failed, to help find out why,
+        // so the program's error is reported alongside any from the images
+        let mut errors: Vec<Error> = result.as_ref().err().cloned().into_iter().collect();
+        if let Some(path) = dump_grid {
+            if let Err(err) = image::render_grid(&machine).and_then(|grid| image::save(&grid, path)) {
+                errors.push(err);
+            }
+        }
+        if let (Some(path), Some(recorder)) = (record, recorder) {
+            if let Err(err) = recorder.save(&machine, path) {
+                errors.push(err);
+            }
+        }
+
+        return match errors.is_empty() {
+            true => result.map_err(|e| vec![e]),
+            false => Err(errors)
+        };
+    } else if let Some(matches) = matches.subcommand_matches("debug") {
+        let program = load_program(matches, sources)?;
+
+        return Ok(debug::debug(&program, sources, &mut io::stdin().lock(), &mut io::stdout().lock()));
+    } else if let Some(matches) = matches.subcommand_matches("test") {
+        let paths: Vec<&str> = matches.values_of("PATH").map_or(vec!["tests", "examples"], |paths| paths.collect());
+
+        return test_programs(&paths, matches.is_present("bless")).map_err(|e| vec![e]);
     } else {
         unreachable!()
     }
 
-    Ok(())
+    Ok(0)
+}
+
+// Snapshot tests every `.lat` file in `paths` (directories are searched one
+// level deep), or records new snapshots when blessing
+fn test_programs(paths: &[&str], bless: bool) -> Result<i32, Error> {
+    let lattice = std::env::current_exe()
+        .map_err(|err| Error::without_pos(format!("Unable to find the lattice executable: {}", err)))?;
+
+    let mut programs: Vec<PathBuf> = Vec::new();
+    for path in paths {
+        let path = Path::new(path);
+
+        if path.is_dir() {
+            let entries = fs::read_dir(path)
+                .map_err(|err| Error::without_pos(format!("Unable to read {}: {}", path.display(), err)))?;
+            let mut found: Vec<PathBuf> = entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "lat"))
+                .collect();
+            found.sort();
+            programs.extend(found);
+        } else {
+            programs.push(path.to_path_buf());
+        }
+    }
+
+    let mut failed = 0;
+
+    for program in &programs {
+        let expected_path = snapshot::path_for(program);
+        let expected = if expected_path.exists() { Some(Snapshot::load(&expected_path)?) } else { None };
+
+        // Inputs are written by hand, so blessing keeps them
+        let stdin = expected.as_ref().map_or(&[][..], |expected| &expected.stdin[..]);
+        let actual = Snapshot::record(&lattice, program, stdin)?;
+
+        if bless {
+            actual.save(&expected_path)?;
+            println!("BLESS {}", program.display());
+            continue;
+        }
+
+        let diffs = match &expected {
+            Some(expected) => actual.diff(expected),
+            None => vec![format!("missing {}; run `lattice test --bless` to record it", expected_path.display())]
+        };
+
+        if diffs.is_empty() {
+            println!("PASS  {}", program.display());
+        } else {
+            failed += 1;
+            println!("FAIL  {}", program.display());
+            for diff in diffs {
+                println!("  {}", diff.replace('\n', "\n  "));
+            }
+        }
+    }
+
+    if !bless {
+        println!("\n{} passed, {} failed", programs.len() - failed, failed);
+    }
+
+    Ok(if failed == 0 { 0 } else { 1 })
 }
@@ -0,0 +1,574 @@
+use std::collections::HashMap;
+
+use super::{ CellWidth, Error, FileId, SourceMap, Token, TokenPos };
+use super::check::StackEffect;
+use super::lex::{ self, Lexeme, LexemeKind };
+
+#[derive(Debug, Default)]
+pub struct Program {
+    // Indexed by `Token::FnCall`
+    pub functions: Vec<FnDef>,
+    // Indexed by `Token::Str`
+    pub strings: Vec<Vec<u8>>,
+    pub body: Vec<Node>,
+    // Set with `#cellwidth`, or overridden from the command line
+    pub cell_width: CellWidth
+}
+
+#[derive(Debug)]
+pub struct FnDef {
+    pub name: String,
+    pub pos: TokenPos,
+    // Declared as `fn name int int -- int`, with the position of the `--`
+    pub signature: Option<(StackEffect, TokenPos)>,
+    pub body: Vec<Node>,
+    pub end: TokenPos
+}
+
+#[derive(Debug)]
+pub enum Node {
+    Word(Token, TokenPos),
+    If(If),
+    While(While),
+}
+
+#[derive(Debug)]
+pub struct If {
+    pub pos: TokenPos,
+    pub then_body: Vec<Node>,
+    pub else_body: Option<(TokenPos, Vec<Node>)>,
+    pub end: TokenPos
+}
+
+#[derive(Debug)]
+pub struct While {
+    pub pos: TokenPos,
+    pub cond: Vec<Node>,
+    pub do_pos: TokenPos,
+    pub body: Vec<Node>,
+    pub end: TokenPos
+}
+
+// Parsed nodes plus the keyword (and its position) that closed the block
+type Block<'a> = (Vec<Node>, Option<(&'a str, TokenPos)>);
+
+const KEYWORDS: [&str; 6] = ["if", "else", "while", "do", "end", "fn"];
+
+// Every stack value is an integer; the type names only document intent
+const TYPES: [&str; 1] = ["int"];
+
+// Every word the parser understands, used for suggestions on typos
+const BUILTINS: [&str; 28] = [
+    "+", "-", "*", "/", "=", ">", "<", "and", "not", "or", "print", "write", "emit", "puts", "key", "readln",
+    "dup", "drop", "swap", "over", "u", "d", "l", "r", "loc", ".", ",", "?"
+];
+
+// Errors are collected rather than returned so that a single run reports
+// every problem in the file; malformed constructs are skipped.
+struct Parser<'a> {
+    lexemes: &'a [Lexeme],
+    index: usize,
+    functions: HashMap<&'a str, (usize, TokenPos)>,
+    consts: HashMap<&'a str, u64>,
+    strings: Vec<Vec<u8>>,
+    cell_width: Option<(CellWidth, TokenPos)>,
+    errors: Vec<Error>
+}
+
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> Option<&'a Lexeme> {
+        let lexeme = self.lexemes.get(self.index)?;
+        self.index += 1;
+
+        Some(lexeme)
+    }
+
+    fn peek_keyword(&self) -> Option<(&'a str, TokenPos)> {
+        match self.lexemes.get(self.index) {
+            Some(Lexeme { kind: LexemeKind::Word(w), pos }) if KEYWORDS.contains(&w.as_str()) => {
+                Some((w.as_str(), *pos))
+            },
+            _ => None
+        }
+    }
+
+    // Parses nodes until one of `terminators` (which is consumed and returned)
+    // or the end of the file. Any other block keyword out of place is an error.
+    fn parse_block(&mut self, terminators: &[&str]) -> Block<'a> {
+        let mut nodes: Vec<Node> = Vec::new();
+
+        loop {
+            if let Some((keyword, pos)) = self.peek_keyword() {
+                match keyword {
+                    k if terminators.contains(&k) => {
+                        self.index += 1;
+                        return (nodes, Some((k, pos)));
+                    },
+                    "else" | "do" | "end" => {
+                        self.index += 1;
+                        self.errors.push(
+                            Error::new(format!("Unexpected `{}` without a matching block.", keyword), pos)
+                                .with_help(format!("Remove this `{}` or open a block before it.", keyword))
+                        );
+                        continue;
+                    },
+                    "fn" => {
+                        self.index += 1;
+                        self.errors.push(
+                            Error::new("Functions can only be defined at the top level.", pos)
+                                .with_help("Move this function definition out of the enclosing block.")
+                        );
+                        self.parse_fn(pos);
+                        continue;
+                    },
+                    _ => { }
+                }
+            }
+
+            let lexeme = match self.next() {
+                Some(l) => l,
+                None => return (nodes, None)
+            };
+            let pos = lexeme.pos;
+
+            let node = match &lexeme.kind {
+                LexemeKind::Directive(name) if name == "const" => {
+                    self.parse_const(pos);
+                    continue;
+                },
+                LexemeKind::Directive(name) if name == "cellwidth" => {
+                    self.parse_cell_width(pos);
+                    continue;
+                },
+                LexemeKind::Directive(name) => {
+                    self.errors.push(
+                        Error::new(format!("Unknown directive #{}", name), pos)
+                            .with_note("The supported directives are `#const` and `#cellwidth`.")
+                    );
+                    continue;
+                },
+                LexemeKind::Int(num) => Node::Word(Token::Num(*num), pos),
+                LexemeKind::Str(bytes, direction) => {
+                    self.strings.push(bytes.clone());
+                    Node::Word(Token::Str(self.strings.len() - 1, *direction), pos)
+                },
+                LexemeKind::Word(w) => match w.as_str() {
+                    "if" => Node::If(self.parse_if(pos)),
+                    "while" => Node::While(self.parse_while(pos)),
+                    w => match self.parse_word(w, pos) {
+                        Ok(token) => Node::Word(token, pos),
+                        Err(err) => {
+                            self.errors.push(err);
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            nodes.push(node);
+        }
+    }
+
+    fn parse_word(&self, word: &str, pos: TokenPos) -> Result<Token, Error> {
+        let token = match word {
+            w if self.consts.contains_key(w) => Token::Num(self.consts[w]),
+            w if self.functions.contains_key(w) => Token::FnCall(self.functions[w].0),
+            "+" => Token::OpAdd,
+            "-" => Token::OpSub,
+            "*" => Token::OpMul,
+            "/" => Token::OpDiv,
+            "=" => Token::Eq,
+            ">" => Token::GT,
+            "<" => Token::LT,
+            "and" => Token::And,
+            "not" => Token::Not,
+            "or" => Token::Or,
+            "print" => Token::Print,
+            "write" => Token::Write,
+            "emit" => Token::Emit,
+            "puts" => Token::Puts,
+            "key" => Token::Key,
+            "readln" => Token::ReadLine,
+            "dup" => Token::Dup,
+            "drop" => Token::Drop,
+            "swap" => Token::Swap,
+            "over" => Token::Over,
+            "u" => Token::Up,
+            "d" => Token::Down,
+            "l" => Token::Left,
+            "r" => Token::Right,
+            "loc" => Token::Loc,
+            "." => Token::Store,
+            "," => Token::Load,
+            "?" => Token::Copy,
+            _ => {
+                let mut err = Error::new(format!("Unknown word `{}`", word), pos);
+
+                let candidates = BUILTINS.iter().chain(KEYWORDS.iter()).cloned()
+                    .chain(self.functions.keys().cloned())
+                    .chain(self.consts.keys().cloned());
+                if let Some(suggestion) = suggest(word, candidates) {
+                    err = err.with_help(format!("Did you mean `{}`?", suggestion));
+                }
+
+                return Err(err);
+            },
+        };
+
+        Ok(token)
+    }
+
+    fn parse_const(&mut self, pos: TokenPos) {
+        match (self.next(), self.next()) {
+            (Some(Lexeme { kind: LexemeKind::Word(name), .. }), Some(Lexeme { kind: LexemeKind::Int(val), .. })) => {
+                self.consts.insert(name, *val);
+            },
+            _ => self.errors.push(
+                Error::new("Unable to parse const declaration.", pos)
+                    .with_help("Constants are declared as `#const NAME VALUE`.")
+            )
+        }
+    }
+
+    fn parse_cell_width(&mut self, pos: TokenPos) {
+        let width = match self.next() {
+            Some(Lexeme { kind: LexemeKind::Word(name), .. }) => name.parse::<CellWidth>(),
+            _ => Err("Unable to parse cell width declaration.".to_string())
+        };
+
+        match (width, self.cell_width) {
+            (Err(msg), _) => self.errors.push(
+                Error::new(msg, pos)
+                    .with_help("The cell width is declared as `#cellwidth u8`, `u16`, `u32` or `u64`.")
+            ),
+            (Ok(width), Some((first, first_pos))) if width != first => self.errors.push(
+                Error::new(format!("Cell width is declared as both {} and {}.", first, width), pos)
+                    .with_note(format!("It was first declared at {}.", first_pos))
+            ),
+            (Ok(width), _) => self.cell_width = Some((width, pos))
+        }
+    }
+
+    fn parse_if(&mut self, pos: TokenPos) -> If {
+        let (then_body, terminator) = self.parse_block(&["else", "end"]);
+
+        match terminator {
+            Some(("else", else_pos)) => {
+                let (else_body, terminator) = self.parse_block(&["end"]);
+                let end = self.expect_end(terminator, "If", pos);
+
+                If { pos, then_body, else_body: Some((else_pos, else_body)), end }
+            },
+            Some((_, end)) => If { pos, then_body, else_body: None, end },
+            None => {
+                let end = self.expect_end(None, "If", pos);
+                If { pos, then_body, else_body: None, end }
+            }
+        }
+    }
+
+    fn parse_while(&mut self, pos: TokenPos) -> While {
+        let (cond, terminator) = self.parse_block(&["do"]);
+        let do_pos = match terminator {
+            Some((_, do_pos)) => do_pos,
+            None => {
+                self.errors.push(
+                    Error::new("While loop condition is not followed by the `do` keyword.", pos)
+                        .with_help("Loops are written as `while <condition> do <body> end`.")
+                );
+                pos
+            }
+        };
+
+        let (body, terminator) = self.parse_block(&["end"]);
+        let end = self.expect_end(terminator, "While", pos);
+
+        While { pos, cond, do_pos, body, end }
+    }
+
+    fn parse_fn(&mut self, pos: TokenPos) -> FnDef {
+        let name = match self.next() {
+            Some(Lexeme { kind: LexemeKind::Word(name), .. }) => name.clone(),
+            _ => {
+                self.errors.push(
+                    Error::new("Expected a function name after `fn`.", pos)
+                        .with_help("Functions are written as `fn <name> <body> end`.")
+                );
+                String::new()
+            }
+        };
+
+        let signature = self.parse_signature(pos);
+
+        let (body, terminator) = self.parse_block(&["end"]);
+        let end = self.expect_end(terminator, "Function", pos);
+
+        FnDef { name, pos, signature, body, end }
+    }
+
+    // Parses an optional `int int -- int` following a function's name
+    fn parse_signature(&mut self, fn_pos: TokenPos) -> Option<(StackEffect, TokenPos)> {
+        let is_signature_word = |lexeme: Option<&Lexeme>| matches!(
+            lexeme,
+            Some(Lexeme { kind: LexemeKind::Word(w), .. }) if w == "--" || TYPES.contains(&w.as_str())
+        );
+
+        if !is_signature_word(self.lexemes.get(self.index)) {
+            return None;
+        }
+
+        let mut effect = StackEffect::default();
+
+        let separator = loop {
+            match self.lexemes.get(self.index) {
+                Some(Lexeme { kind: LexemeKind::Word(w), pos }) if w == "--" => {
+                    self.index += 1;
+                    break *pos;
+                },
+                Some(Lexeme { kind: LexemeKind::Word(w), .. }) if TYPES.contains(&w.as_str()) => {
+                    self.index += 1;
+                    effect.inputs += 1;
+                },
+                Some(Lexeme { kind: LexemeKind::Word(w), pos }) if !KEYWORDS.contains(&w.as_str()) => {
+                    self.index += 1;
+                    self.errors.push(
+                        Error::new(format!("Unknown type `{}` in function signature.", w), *pos)
+                            .with_note(format!("The supported types are: {}.", TYPES.join(", ")))
+                    );
+                },
+                _ => {
+                    self.errors.push(
+                        Error::new("Function signature is missing the `--` separator.", fn_pos)
+                            .with_help("Signatures are written as `fn <name> <inputs> -- <outputs>`.")
+                    );
+                    return None;
+                }
+            }
+        };
+
+        while is_signature_word(self.lexemes.get(self.index)) {
+            let lexeme = &self.lexemes[self.index];
+            self.index += 1;
+
+            if let LexemeKind::Word(w) = &lexeme.kind {
+                if w == "--" {
+                    self.errors.push(Error::new("Function signatures can only contain one `--`.", lexeme.pos));
+                } else {
+                    effect.outputs += 1;
+                }
+            }
+        }
+
+        Some((effect, separator))
+    }
+
+    fn expect_end(&mut self, terminator: Option<(&str, TokenPos)>, block: &str, pos: TokenPos) -> TokenPos {
+        match terminator {
+            Some((_, end)) => end,
+            None => {
+                self.errors.push(
+                    Error::new(format!("{} block is not terminated with the `end` keyword.", block), pos)
+                        .with_note("The block is still open at the end of the file.")
+                );
+                pos
+            }
+        }
+    }
+}
+
+// Returns the candidate closest to `word` if it is within a couple of edits
+fn suggest<'a>(word: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    fn distance(a: &str, b: &str) -> usize {
+        let b: Vec<char> = b.chars().collect();
+        let mut row: Vec<usize> = (0..=b.len()).collect();
+
+        for (i, ca) in a.chars().enumerate() {
+            let mut prev = row[0];
+            row[0] = i + 1;
+
+            for (j, cb) in b.iter().enumerate() {
+                let current = row[j + 1];
+                row[j + 1] = if ca == *cb {
+                    prev
+                } else {
+                    1 + prev.min(row[j]).min(row[j + 1])
+                };
+                prev = current;
+            }
+        }
+
+        row[b.len()]
+    }
+
+    let max_distance = if word.chars().count() <= 3 { 1 } else { 2 };
+
+    candidates
+        .map(|c| (distance(word, c), c))
+        .filter(|(d, c)| *d <= max_distance && c.chars().count() > 1)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, c)| c)
+}
+
+// Collects every `fn NAME` up front so functions can be called before
+// (or from within) their own definition.
+fn collect_functions<'a>(lexemes: &'a [Lexeme], errors: &mut Vec<Error>) -> HashMap<&'a str, (usize, TokenPos)> {
+    let mut functions: HashMap<&str, (usize, TokenPos)> = HashMap::new();
+
+    for pair in lexemes.windows(2) {
+        if let [Lexeme { kind: LexemeKind::Word(f), .. }, Lexeme { kind: LexemeKind::Word(name), pos }] = pair {
+            if f != "fn" {
+                continue;
+            }
+
+            if KEYWORDS.contains(&name.as_str()) {
+                errors.push(Error::new(format!("`{}` is a keyword and cannot be used as a function name.", name), *pos));
+                continue;
+            }
+
+            if let Some((_, first)) = functions.get(name.as_str()) {
+                errors.push(
+                    Error::new(format!("Function `{}` is already defined.", name), *pos)
+                        .with_note(format!("`{}` was first defined at {}.", name, first))
+                );
+                continue;
+            }
+
+            let index = functions.len();
+            functions.insert(name, (index, *pos));
+        }
+    }
+
+    functions
+}
+
+pub fn parse_file(sources: &SourceMap, file: FileId) -> Result<Program, Vec<Error>> {
+    let mut errors: Vec<Error> = Vec::new();
+    let lexemes = lex::tokenize(file, &sources.get(file).text, &mut errors);
+    let functions = collect_functions(&lexemes, &mut errors);
+
+    let mut parser = Parser {
+        lexemes: &lexemes,
+        index: 0,
+        functions,
+        consts: HashMap::new(),
+        strings: Vec::new(),
+        cell_width: None,
+        errors
+    };
+
+    let mut program = Program::default();
+
+    loop {
+        let (nodes, terminator) = parser.parse_block(&["fn"]);
+        program.body.extend(nodes);
+
+        match terminator {
+            Some((_, pos)) => {
+                let function = parser.parse_fn(pos);
+                program.functions.push(function);
+            },
+            None => break
+        }
+    }
+
+    program.strings = parser.strings;
+    program.cell_width = parser.cell_width.map_or(CellWidth::default(), |(width, _)| width);
+
+    if parser.errors.is_empty() {
+        Ok(program)
+    } else {
+        let mut errors = parser.errors;
+        errors.sort_by_key(|err| err.pos().map(|pos| (pos.file, pos.offset)));
+
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_sources;
+
+    fn errors(src: &str) -> Vec<(String, usize, usize)> {
+        let (sources, file) = test_sources(src);
+
+        parse_file(&sources, file).unwrap_err().iter().map(|err| {
+            let pos = err.pos().unwrap();
+            (err.msg().to_string(), pos.line, pos.col)
+        }).collect()
+    }
+
+    fn help(src: &str) -> Option<String> {
+        let (sources, file) = test_sources(src);
+
+        match &parse_file(&sources, file).unwrap_err()[..] {
+            [Error::Diagnostic(diagnostic)] => diagnostic.help.clone(),
+            errors => panic!("expected one diagnostic, got {:?}", errors)
+        }
+    }
+
+    #[test]
+    fn stray_keywords() {
+        assert_eq!(errors("1 else 2 do
+end"), vec![
+            (String::from("Unexpected `else` without a matching block."), 1, 3),
+            (String::from("Unexpected `do` without a matching block."), 1, 10),
+            (String::from("Unexpected `end` without a matching block."), 2, 1),
+        ]);
+    }
+
+    #[test]
+    fn unterminated_blocks() {
+        assert_eq!(errors("1 if 2"), vec![
+            (String::from("If block is not terminated with the `end` keyword."), 1, 3)
+        ]);
+        assert_eq!(errors("while 1"), vec![
+            (String::from("While loop condition is not followed by the `do` keyword."), 1, 1),
+            (String::from("While block is not terminated with the `end` keyword."), 1, 1),
+        ]);
+        assert_eq!(errors("fn foo
+    1"), vec![
+            (String::from("Function block is not terminated with the `end` keyword."), 1, 1)
+        ]);
+    }
+
+    #[test]
+    fn functions_only_at_the_top_level() {
+        assert_eq!(errors("1 if
+    fn foo end
+end"), vec![
+            (String::from("Functions can only be defined at the top level."), 2, 5)
+        ]);
+    }
+
+    #[test]
+    fn function_names() {
+        assert_eq!(errors("fn foo end
+fn foo end"), vec![
+            (String::from("Function `foo` is already defined."), 2, 4)
+        ]);
+        assert_eq!(errors("fn while end"), vec![
+            (String::from("`while` is a keyword and cannot be used as a function name."), 1, 4)
+        ]);
+    }
+
+    #[test]
+    fn const_declarations() {
+        assert_eq!(errors("#const 5 N"), vec![
+            (String::from("Unable to parse const declaration."), 1, 1)
+        ]);
+        assert_eq!(errors("#const N"), vec![
+            (String::from("Unable to parse const declaration."), 1, 1)
+        ]);
+    }
+
+    #[test]
+    fn suggestions() {
+        assert_eq!(help("1 prnt"), Some(String::from("Did you mean `print`?")));
+        assert_eq!(help("fn square dup * end
+2 sqare"), Some(String::from("Did you mean `square`?")));
+        assert_eq!(help("#const WIDTH 8
+WIDHT"), Some(String::from("Did you mean `WIDTH`?")));
+        assert_eq!(help("1 frobnicate"), None);
+    }
+}
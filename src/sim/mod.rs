@@ -1,199 +1,755 @@
 use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{ self, BufRead, Write };
 
-use super::{ Error, Token, TokenPos };
+use super::{ diag, Direction, Error, SourceMap, Token, TokenPos, MAX_CALL_DEPTH };
+use super::parse::{ Node, Program };
 
-pub fn simulate(tokens: &Vec<(Token, TokenPos)>) -> Result<(), Error> {
-    let mut mem_addr: (u32, u32, u64) = (0, 0, 0);
-    let mut mem: BTreeMap<u64, usize> = BTreeMap::new();
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    // `FILE:LINE:COL  TOKEN  (X, Y)  [BEFORE] -> [AFTER]`
+    Human,
+    // One JSON object per line
+    Json,
+}
+
+impl std::str::FromStr for TraceFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(TraceFormat::Human),
+            "json" => Ok(TraceFormat::Json),
+            _ => Err(Error::without_pos(format!("Unknown trace format: {}", s)))
+        }
+    }
+}
 
-    let mut stack: Vec<usize> = Vec::new();
-    let mut ip: usize = 0;
+#[derive(Debug, Clone, Copy)]
+enum Instr {
+    Word(Token),
+    // Pops the top of the stack and jumps to the given ip if it is zero
+    JumpIfZero(usize),
+    Jump(usize),
+    // Pushes a frame onto the call stack and jumps to the function's first
+    // instruction
+    Call(usize),
+    Ret,
+}
+
+fn flatten(nodes: &[Node], instrs: &mut Vec<(Instr, TokenPos)>) {
+    for node in nodes {
+        match node {
+            Node::Word(Token::FnCall(index), pos) => instrs.push((Instr::Call(*index), *pos)),
+            Node::Word(token, pos) => instrs.push((Instr::Word(*token), *pos)),
+            Node::If(block) => {
+                let jump_ip = instrs.len();
+                instrs.push((Instr::JumpIfZero(0), block.pos));
+                flatten(&block.then_body, instrs);
+
+                if let Some((else_pos, else_body)) = &block.else_body {
+                    let else_ip = instrs.len();
+                    instrs.push((Instr::Jump(0), *else_pos));
+                    instrs[jump_ip].0 = Instr::JumpIfZero(instrs.len());
+                    flatten(else_body, instrs);
+                    instrs[else_ip].0 = Instr::Jump(instrs.len());
+                } else {
+                    instrs[jump_ip].0 = Instr::JumpIfZero(instrs.len());
+                }
+            },
+            Node::While(block) => {
+                let start_ip = instrs.len();
+                flatten(&block.cond, instrs);
+
+                let jump_ip = instrs.len();
+                instrs.push((Instr::JumpIfZero(0), block.do_pos));
+                flatten(&block.body, instrs);
+                instrs.push((Instr::Jump(start_ip), block.end));
+                instrs[jump_ip].0 = Instr::JumpIfZero(instrs.len());
+            }
+        }
+    }
+}
+
+// Lays out every function followed by the main body. Returns the
+// instructions, the address of each function and the entry ip.
+fn link(program: &Program) -> (Vec<(Instr, TokenPos)>, Vec<usize>, usize) {
+    let mut instrs: Vec<(Instr, TokenPos)> = Vec::new();
+    let mut fn_addrs: Vec<usize> = Vec::with_capacity(program.functions.len());
+
+    for function in &program.functions {
+        fn_addrs.push(instrs.len());
+        flatten(&function.body, &mut instrs);
+        instrs.push((Instr::Ret, function.end));
+    }
+
+    let entry = instrs.len();
+    flatten(&program.body, &mut instrs);
+
+    (instrs, fn_addrs, entry)
+}
 
-    let mut function_call_stack: Vec<usize> = Vec::new();
+// Moves the grid pointer `n` cells, or returns `None` if that would leave the
+// grid. Cells are addressed as `y * 2^32 + x`.
+fn step(mem_addr: (u32, u32, u64), direction: Direction, n: u64) -> Option<(u32, u32, u64)> {
+    let (x, y, _) = mem_addr;
+    let n = u32::try_from(n).ok()?;
 
-    while ip < tokens.len() {
-        let (token, _) = &tokens[ip];
+    let (x, y) = match direction {
+        Direction::Up => (x, y.checked_sub(n)?),
+        Direction::Down => (x, y.checked_add(n)?),
+        Direction::Left => (x.checked_sub(n)?, y),
+        Direction::Right => (x.checked_add(n)?, y),
+    };
+
+    Some((x, y, ((y as u64) << 32) | x as u64))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    // Index into `Program::functions`
+    pub function: usize,
+    // Where the function was called from
+    pub call_pos: TokenPos,
+    return_ip: usize
+}
+
+// What a step of `Machine::run_with` did
+#[derive(Debug, Clone)]
+pub struct Step {
+    // The token that ran
+    pub pos: TokenPos,
+    // `None` for control flow (`if`, `do`, `end`, function calls, ...)
+    pub token: Option<Token>,
+    // Grid pointer and stack before the token ran
+    pub pointer: (u32, u32),
+    pub before: Vec<u64>
+}
+
+// Logs a step of `Machine::run_with` as one line: the token, where it is, the
+// grid pointer it ran at and the stack before and after it
+pub fn write_trace(trace: &mut dyn Write, sources: &SourceMap, format: TraceFormat, machine: &Machine, step: &Step) {
+    let values = |stack: &[u64], sep: &str| stack.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(sep);
+
+    let pos = step.pos;
+    let (x, y) = step.pointer;
+    let file = sources.get(pos.file);
+    let token = &file.text[pos.offset..pos.offset + pos.len];
+
+    let _ = match format {
+        TraceFormat::Human => writeln!(trace, "{}:{}  {}  ({}, {})  [{}] -> [{}]",
+            file.name, pos, token.replace('\n', "\\n"), x, y, values(&step.before, ", "), values(&machine.stack, ", ")),
+        TraceFormat::Json => writeln!(trace,
+            "{{\"file\":{},\"line\":{},\"column\":{},\"token\":{},\"pointer\":[{},{}],\"before\":[{}],\"after\":[{}]}}",
+            diag::json_string(&file.name), pos.line, pos.col, diag::json_string(token), x, y,
+            values(&step.before, ","), values(&machine.stack, ","))
+    };
+}
+
+// The simulator's state between two instructions, so a program can be run to
+// completion or stepped through one token at a time
+pub struct Machine<'a> {
+    program: &'a Program,
+    instrs: Vec<(Instr, TokenPos)>,
+    fn_addrs: Vec<usize>,
+    ip: usize,
+
+    // Values are unsigned 64-bit integers; arithmetic wraps around
+    pub stack: Vec<u64>,
+    pub call_stack: Vec<Frame>,
+    pub mem_addr: (u32, u32, u64),
+    // Cells that were never stored are absent and read as 0
+    pub mem: BTreeMap<u64, u64>
+}
+
+impl<'a> Machine<'a> {
+    pub fn new(program: &'a Program) -> Self {
+        let (instrs, fn_addrs, entry) = link(program);
+
+        Machine {
+            program,
+            instrs,
+            fn_addrs,
+            ip: entry,
+            stack: Vec::new(),
+            call_stack: Vec::new(),
+            mem_addr: (0, 0, 0),
+            mem: BTreeMap::new()
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.ip >= self.instrs.len()
+    }
+
+    // Position of the token that runs next, or `None` once the program is done
+    pub fn pos(&self) -> Option<TokenPos> {
+        self.instrs.get(self.ip).map(|(_, pos)| *pos)
+    }
+
+    // Whether the next instruction is the first one of a function
+    pub fn entering(&self) -> Option<usize> {
+        self.fn_addrs.iter().position(|addr| *addr == self.ip)
+    }
+
+    // Whether any instruction comes from `line`, i.e. the machine can stop there
+    pub fn has_code_on(&self, line: usize) -> bool {
+        self.instrs.iter().any(|(_, pos)| pos.line == line)
+    }
+
+    pub fn cell(&self, x: u32, y: u32) -> u64 {
+        *self.mem.get(&(((y as u64) << 32) | x as u64)).unwrap_or(&0)
+    }
+
+    // The low byte of the value on top of the stack, or 0 if it is empty
+    pub fn exit_code(&self) -> i32 {
+        self.stack.last().map_or(0, |value| *value as u8 as i32)
+    }
+
+    // Runs until the program finishes, returning its exit code
+    pub fn run(&mut self, input: &mut dyn BufRead, output: &mut dyn Write) -> Result<i32, Error> {
+        while !self.is_finished() {
+            if let Err(err) = self.step(input, output) {
+                let _ = output.flush();
+                return Err(err);
+            }
+        }
+
+        let _ = output.flush();
+
+        Ok(self.exit_code())
+    }
+
+    // Like `run`, but calls `observe` after every step with what it did. An
+    // error from `observe` stops the program.
+    pub fn run_with(
+        &mut self, input: &mut dyn BufRead, output: &mut dyn Write,
+        observe: &mut dyn FnMut(&Machine, &Step) -> Result<(), Error>
+    ) -> Result<i32, Error> {
+        let mut result = Ok(());
+
+        while let (Some(pos), Ok(())) = (self.pos(), &result) {
+            let step = Step {
+                pos,
+                token: match self.instrs[self.ip].0 {
+                    Instr::Word(token) => Some(token),
+                    _ => None
+                },
+                pointer: (self.mem_addr.0, self.mem_addr.1),
+                before: self.stack.clone()
+            };
+
+            result = self.step(input, output).and_then(|_| observe(self, &step));
+        }
+
+        let _ = output.flush();
+
+        result.map(|_| self.exit_code())
+    }
+
+    // Executes a single instruction. On error the machine is left at the
+    // failing instruction.
+    pub fn step(&mut self, input: &mut dyn BufRead, output: &mut dyn Write) -> Result<(), Error> {
+        let (instr, pos) = match self.instrs.get(self.ip) {
+            Some((instr, pos)) => (*instr, *pos),
+            None => return Ok(())
+        };
+
+        let stack = &mut self.stack;
+        let mem = &mut self.mem;
+        let mem_addr = &mut self.mem_addr;
+        // Values are truncated to the cell width when stored
+        let cell_width = self.program.cell_width;
+
+        // Builds a `RuntimeError` for the current token with a snapshot of the machine
+        macro_rules! runtime_error {
+            ($($msg:tt)*) => {
+                Error::runtime(format!($($msg)*), pos, stack, (mem_addr.0, mem_addr.1))
+            }
+        }
+
+        // Pops `n` elements as an array (top of the stack last), leaving the
+        // stack untouched if there aren't enough
+        macro_rules! pop {
+            ($n:literal, $msg:expr) => {{
+                if stack.len() < $n {
+                    return Err(runtime_error!($msg));
+                }
+
+                let mut values = [0; $n];
+                values.copy_from_slice(&stack[stack.len() - $n..]);
+                stack.truncate(stack.len() - $n);
+
+                values
+            }}
+        }
+
+        let token = match instr {
+            Instr::Word(token) => token,
+            Instr::JumpIfZero(next_ip) => {
+                let [a] = pop!(1, "No element on stack for conditional jump.");
+                self.ip = if a == 0 { next_ip } else { self.ip + 1 };
+                return Ok(());
+            },
+            Instr::Jump(next_ip) => {
+                self.ip = next_ip;
+                return Ok(());
+            },
+            Instr::Call(function) => {
+                if self.call_stack.len() >= MAX_CALL_DEPTH {
+                    return Err(runtime_error!("Too many nested function calls."));
+                }
+                self.call_stack.push(Frame { function, call_pos: pos, return_ip: self.ip + 1 });
+                self.ip = self.fn_addrs[function];
+                return Ok(());
+            },
+            Instr::Ret => {
+                let frame = self.call_stack.pop().expect("Returned from a function that was never called.");
+                self.ip = frame.return_ip;
+                return Ok(());
+            }
+        };
 
         match token {
             Token::Num(num) => {
-                stack.push(num.clone());
+                stack.push(num);
             },
             Token::OpAdd => {
-                let a = stack.pop().expect("Not enough elements on the stack to add.");
-                let b = stack.pop().expect("Not enough elements on the stack to add.");
-                stack.push(a + b);
+                let [b, a] = pop!(2, "Not enough elements on the stack to add.");
+                stack.push(a.wrapping_add(b));
             },
             Token::OpSub => {
-                let a = stack.pop().expect("Not enough elements on the stack to subtract.");
-                let b = stack.pop().expect("Not enough elements on the stack to subtract.");
-                stack.push(b - a);
+                let [b, a] = pop!(2, "Not enough elements on the stack to subtract.");
+                stack.push(b.wrapping_sub(a));
             },
             Token::OpMul => {
-                let a = stack.pop().expect("Not enough elements on the stack to multiply.");
-                let b = stack.pop().expect("Not enough elements on the stack to multiply.");
-                stack.push(a * b);
+                let [b, a] = pop!(2, "Not enough elements on the stack to multiply.");
+                stack.push(a.wrapping_mul(b));
             },
             Token::OpDiv => {
-                let a = stack.pop().expect("Not enough elements on the stack to divide.");
-                let b = stack.pop().expect("Not enough elements on the stack to divide.");
+                let [b, a] = pop!(2, "Not enough elements on the stack to divide.");
+                if a == 0 {
+                    stack.push(b);
+                    stack.push(a);
+                    return Err(runtime_error!("Division by zero."));
+                }
                 stack.push(b / a);
             },
             Token::Print => {
-                let a = stack.pop().expect("Not enough elements on the stack to print.");
-                println!("{}", a);
+                let [a] = pop!(1, "Not enough elements on the stack to print.");
+                let _ = writeln!(output, "{}", a);
             },
             Token::Write => {
-                let a = stack.pop().expect("Need length to write.");
-                let (_, _, mut addr) = mem_addr;
+                let [a] = pop!(1, "Need length to write.");
+                let (_, _, mut addr) = *mem_addr;
                 for _ in 0..a {
-                    print!("{} ", mem.get(&addr).unwrap_or(&0));
-                    addr += 1;
+                    let _ = write!(output, "{} ", mem.get(&addr).unwrap_or(&0));
+                    addr = addr.wrapping_add(1);
+                }
+                let _ = writeln!(output);
+            },
+            Token::Emit => {
+                let [a] = pop!(1, "Need a value to emit.");
+                let _ = output.write_all(&[a as u8]);
+            },
+            Token::Puts => {
+                let [a] = pop!(1, "Need length to puts.");
+                let (_, _, addr) = *mem_addr;
+                // Write as we go: the count can be far larger than what fits
+                // in memory, and a closed pipe should end it early
+                for i in 0..a {
+                    let byte = *mem.get(&addr.wrapping_add(i)).unwrap_or(&0) as u8;
+                    if output.write_all(&[byte]).is_err() {
+                        break;
+                    }
+                }
+            },
+            Token::Key => {
+                let _ = output.flush();
+
+                let mut byte = [0; 1];
+                match input.read(&mut byte) {
+                    Ok(1) => stack.push(byte[0] as u64),
+                    _ => stack.push(u64::MAX)
+                }
+            },
+            Token::ReadLine => {
+                let _ = output.flush();
+
+                let mut line: Vec<u8> = Vec::new();
+                let _ = input.read_until(b'\n', &mut line);
+                if line.last() == Some(&b'\n') {
+                    line.pop();
+                }
+
+                let (_, _, addr) = *mem_addr;
+                for (i, byte) in line.iter().enumerate() {
+                    mem.insert(addr.wrapping_add(i as u64), *byte as u64);
                 }
-                println!();
+                stack.push(line.len() as u64);
             },
             Token::Dup => {
-                let a = stack.pop().expect("No element to duplicate.");
+                let [a] = pop!(1, "No element to duplicate.");
                 stack.push(a);
                 stack.push(a);
             },
             Token::Drop => {
-                let _ = stack.pop().expect("No element to drop.");
+                let _ = pop!(1, "No element to drop.");
             },
             Token::Swap => {
-                let a = stack.pop().expect("Not enough elements to swap.");
-                let b = stack.pop().expect("Not enough elements to swap.");
+                let [b, a] = pop!(2, "Not enough elements to swap.");
                 stack.push(a);
                 stack.push(b);
             },
             Token::Over => {
-                let a = stack.pop().expect("Not enough elements to duplicate over.");
-                let b = stack.pop().expect("Not enough elements to duplicate over.");
+                let [b, a] = pop!(2, "Not enough elements to duplicate over.");
                 stack.push(b);
                 stack.push(a);
                 stack.push(b);
             },
-            Token::If(next_ip) => {
-                let a = stack.pop().expect("No element on stack for if block.");
-                if a == 0 {
-                    ip = *next_ip;
-                }
-            },
-            Token::Else(end_ip) => { 
-                ip = *end_ip;
-            },
-            Token::While => { },
-            Token::Do(end_ip) => { 
-                let a = stack.pop().expect("Do-while loop must have value on the stack for the condition.");
-                if a == 0 {
-                    ip = *end_ip;
-                }
-            },
-            Token::End(next_ip) => {
-                if *next_ip == -1 {
-                    let next_ip = function_call_stack.pop().unwrap();
-                    ip = next_ip;
-                } else {
-                    ip = *next_ip as usize;
-                }
-            },
             Token::Eq => {
-                let a = stack.pop().expect("No element on stack to compare.");
-                let b = stack.pop().expect("No element on stack to compare.");
-                stack.push((a == b) as usize);
+                let [b, a] = pop!(2, "No element on stack to compare.");
+                stack.push((a == b) as u64);
             },
             Token::GT => {
-                let a = stack.pop().expect("No element on stack to compare.");
-                let b = stack.pop().expect("No element on stack to compare.");
-                stack.push((b > a) as usize);
+                let [b, a] = pop!(2, "No element on stack to compare.");
+                stack.push((b > a) as u64);
             },
             Token::LT => {
-                let a = stack.pop().expect("No element on stack to compare.");
-                let b = stack.pop().expect("No element on stack to compare.");
-                stack.push((b < a) as usize);
+                let [b, a] = pop!(2, "No element on stack to compare.");
+                stack.push((b < a) as u64);
             },
             Token::And => {
-                let a = stack.pop().expect("No element on stack to compare.");
-                let b = stack.pop().expect("No element on stack to compare.");
-                stack.push((a > 0 && b > 0) as usize);
+                let [b, a] = pop!(2, "No element on stack to compare.");
+                stack.push((a != 0 && b != 0) as u64);
             },
             Token::Not => {
-                let a = stack.pop().expect("No element on stack to compare.");
-                stack.push((a == 0) as usize);
+                let [a] = pop!(1, "No element on stack to compare.");
+                stack.push((a == 0) as u64);
             },
             Token::Or => {
-                let a = stack.pop().expect("No element on stack to compare.");
-                let b = stack.pop().expect("No element on stack to compare.");
-                stack.push((a > 0 || b > 0) as usize);
+                let [b, a] = pop!(2, "No element on stack to compare.");
+                stack.push((a != 0 || b != 0) as u64);
             },
             Token::Up => {
-                let a = stack.pop().expect("Up requires a magnitude to traverse the grid.");
-                let (x, y, ptr) = mem_addr;
-                mem_addr = (x, y - a as u32, ptr - (a as u64 * u32::MAX as u64));
+                let [a] = pop!(1, "Up requires a magnitude to traverse the grid.");
+                *mem_addr = match step(*mem_addr, Direction::Up, a) {
+                    Some(addr) => addr,
+                    None => {
+                        stack.push(a);
+                        return Err(runtime_error!("Moving {} cells {} leaves the grid.", a, Direction::Up));
+                    }
+                };
             },
             Token::Down => {
-                let a = stack.pop().expect("Down requires a magnitude to traverse the grid.");
-                let (x, y, ptr) = mem_addr;
-                mem_addr = (x, y + a as u32, ptr + (a as u64 * u32::MAX as u64));
+                let [a] = pop!(1, "Down requires a magnitude to traverse the grid.");
+                *mem_addr = match step(*mem_addr, Direction::Down, a) {
+                    Some(addr) => addr,
+                    None => {
+                        stack.push(a);
+                        return Err(runtime_error!("Moving {} cells {} leaves the grid.", a, Direction::Down));
+                    }
+                };
             },
             Token::Left => {
-                let a = stack.pop().expect("Left requires a magnitude to traverse the grid.");
-                let (x, y, ptr) = mem_addr;
-                mem_addr = (x - a as u32, y, ptr - a as u64);
+                let [a] = pop!(1, "Left requires a magnitude to traverse the grid.");
+                *mem_addr = match step(*mem_addr, Direction::Left, a) {
+                    Some(addr) => addr,
+                    None => {
+                        stack.push(a);
+                        return Err(runtime_error!("Moving {} cells {} leaves the grid.", a, Direction::Left));
+                    }
+                };
             },
             Token::Right => {
-                let a = stack.pop().expect("Right requires a magnitude to traverse the grid.");
-                let (x, y, ptr) = mem_addr;
-                mem_addr = (x + a as u32, y, ptr + a as u64);
+                let [a] = pop!(1, "Right requires a magnitude to traverse the grid.");
+                *mem_addr = match step(*mem_addr, Direction::Right, a) {
+                    Some(addr) => addr,
+                    None => {
+                        stack.push(a);
+                        return Err(runtime_error!("Moving {} cells {} leaves the grid.", a, Direction::Right));
+                    }
+                };
             },
             Token::Loc => {
-                let (_, _, ptr) = mem_addr;
-                stack.push(ptr as usize);
+                let (_, _, ptr) = *mem_addr;
+                stack.push(ptr);
             },
             Token::Store => {
-                let a = stack.pop().expect("There must be a value on the stack to store.");
-                let (_, _, ptr) = mem_addr;
-                mem.insert(ptr, a);
+                let [a] = pop!(1, "There must be a value on the stack to store.");
+                let (_, _, ptr) = *mem_addr;
+                mem.insert(ptr, cell_width.truncate(a));
             },
             Token::Load => {
-                let (_, _, ptr) = mem_addr;
+                let (_, _, ptr) = *mem_addr;
                 if let Some(a) = mem.get_mut(&ptr) {
-                    stack.push(a.clone());
+                    stack.push(*a);
                     *a = 0;
                 } else {
                     stack.push(0);
                 }
             },
             Token::Copy => {
-                let (_, _, ptr) = mem_addr;
+                let (_, _, ptr) = *mem_addr;
                 if let Some(a) = mem.get(&ptr) {
                     stack.push(*a);
                 } else {
                     stack.push(0);
                 }
             },
-            Token::Fn(_, end_ip) => {
-                ip = *end_ip;
-            }
-            Token::FnCall(fn_name) => {
-                function_call_stack.push(ip);
-                let function_ip = tokens.iter().find_map(|(t, pos)| {
-                    if let Token::Fn(f, _) = t {
-                        if f == fn_name {
-                            Some(pos.ip)
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
+            Token::Str(index, direction) => {
+                let bytes = &self.program.strings[index];
+
+                for (i, byte) in bytes.iter().enumerate() {
+                    let (_, _, ptr) = match step(*mem_addr, direction, i as u64) {
+                        Some(addr) => addr,
+                        None => return Err(runtime_error!("String literal of length {} runs {} off the grid.", bytes.len(), direction))
+                    };
+                    mem.insert(ptr, *byte as u64);
+                }
+
+                stack.push(bytes.len() as u64);
+            },
+            Token::FnCall(_) => unreachable!("Function calls are resolved to `Instr::Call` by `link`."),
+        }
+
+        self.ip += 1;
+
+        Ok(())
+    }
+}
+
+// Runs the program on the process's stdin and stdout, returning its exit
+// code: the low byte of the value on top of the stack, or 0 if it is empty.
+pub fn simulate(program: &Program) -> Result<i32, Error> {
+    simulate_with(program, &mut io::stdin().lock(), &mut io::stdout().lock())
+}
+
+pub fn simulate_with(program: &Program, input: &mut dyn BufRead, output: &mut dyn Write) -> Result<i32, Error> {
+    Machine::new(program).run(input, output)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ diag, test_program, RuntimeError, SourceMap };
+
+    // Runs the program without checking it first, so it can underflow
+    fn run(src: &str) -> (SourceMap, Error) {
+        let (sources, program) = test_program(src);
+
+        let err = simulate_with(&program, &mut &b""[..], &mut Vec::new()).unwrap_err();
+        (sources, err)
+    }
+
+    fn runtime_error(err: &Error) -> &RuntimeError {
+        match err {
+            Error::RuntimeError(err) => err,
+            _ => panic!("expected a runtime error, got {:?}", err)
+        }
+    }
+
+    fn trace(src: &str, format: TraceFormat) -> String {
+        let (sources, program) = test_program(src);
+
+        let mut trace: Vec<u8> = Vec::new();
+        Machine::new(&program).run_with(&mut &b""[..], &mut Vec::new(), &mut |machine, step| {
+            write_trace(&mut trace, &sources, format, machine, step);
+            Ok(())
+        }).unwrap();
+
+        String::from_utf8(trace).unwrap()
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Json {
+        Num(u64),
+        Str(String),
+        Array(Vec<Json>),
+        Object(Vec<(String, Json)>),
+    }
+
+    // Just enough JSON for trace lines
+    fn parse_json(chars: &mut std::iter::Peekable<std::str::Chars>) -> Json {
+        match chars.next() {
+            Some('{') => {
+                let mut fields = Vec::new();
+                while chars.peek() != Some(&'}') {
+                    let key = match parse_json(chars) {
+                        Json::Str(key) => key,
+                        other => panic!("expected a key, got {:?}", other)
+                    };
+                    assert_eq!(chars.next(), Some(':'));
+                    fields.push((key, parse_json(chars)));
+                    if chars.peek() == Some(&',') {
+                        chars.next();
+                    }
+                }
+                chars.next();
+                Json::Object(fields)
+            },
+            Some('[') => {
+                let mut values = Vec::new();
+                while chars.peek() != Some(&']') {
+                    values.push(parse_json(chars));
+                    if chars.peek() == Some(&',') {
+                        chars.next();
+                    }
+                }
+                chars.next();
+                Json::Array(values)
+            },
+            Some('"') => {
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => return Json::Str(string),
+                        Some('\\') => string.push(match chars.next() {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some(c) => c,
+                            None => panic!("unterminated escape")
+                        }),
+                        Some(c) => string.push(c),
+                        None => panic!("unterminated string")
                     }
-                }).expect(&format!("No function with name '{:?}'", String::from_utf8_lossy(fn_name)));
+                }
+            },
+            Some(c) if c.is_ascii_digit() => {
+                let mut number = c.to_string();
+                while let Some(c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                    number.push(*c);
+                    chars.next();
+                }
+                Json::Num(number.parse().unwrap())
+            },
+            other => panic!("unexpected {:?}", other)
+        }
+    }
+
+    #[test]
+    fn human_trace() {
+        assert_eq!(trace("42", TraceFormat::Human), "test.lat:1:1  42  (0, 0)  [] -> [42]\n");
+        assert_eq!(trace("4 5 +", TraceFormat::Human).lines().last(), Some("test.lat:1:5  +  (0, 0)  [4, 5] -> [9]"));
+    }
+
+    #[test]
+    fn json_trace() {
+        let trace = trace("3 r\n\"a\\\"b\"d", TraceFormat::Json);
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 3);
+
+        let nums = |values: &[u64]| Json::Array(values.iter().map(|v| Json::Num(*v)).collect());
+        let field = |key: &str, value: Json| (key.to_string(), value);
+
+        let mut chars = lines[2].chars().peekable();
+        assert_eq!(parse_json(&mut chars), Json::Object(vec![
+            field("file", Json::Str("test.lat".into())),
+            field("line", Json::Num(2)),
+            field("column", Json::Num(1)),
+            field("token", Json::Str("\"a\\\"b\"d".into())),
+            field("pointer", nums(&[3, 0])),
+            field("before", nums(&[])),
+            field("after", nums(&[3])),
+        ]));
+        assert_eq!(chars.next(), None);
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let (sources, err) = run("1 print\n7 0 /");
+        let err = runtime_error(&err);
+
+        assert_eq!(err.msg, "Division by zero.");
+        assert_eq!((err.pos.line, err.pos.col), (2, 5));
+        assert_eq!(err.stack, [7, 0]);
+        assert_eq!(err.mem_addr, (0, 0));
+
+        assert_eq!(diag::render(&sources, &Error::RuntimeError(err.clone())), "\
+runtime error: Division by zero.
+ --> test.lat:2:5
+  |
+2 | 7 0 /
+  |     ^
+  = note: stack (top last): [7, 0]
+  = note: grid pointer: (0, 0)
+");
+    }
+
+    #[test]
+    fn stack_underflow() {
+        let (sources, err) = run("2 r 1 d\n5 +");
+        let err = runtime_error(&err);
+
+        assert_eq!(err.msg, "Not enough elements on the stack to add.");
+        assert_eq!((err.pos.line, err.pos.col), (2, 3));
+        // The stack is left as it was before the failing word
+        assert_eq!(err.stack, [5]);
+        assert_eq!(err.mem_addr, (2, 1));
+
+        assert_eq!(
+            diag::render_json(&sources, &Error::RuntimeError(err.clone())),
+            "{\"level\":\"runtime error\",\"message\":\"Not enough elements on the stack to add.\",\
+\"file\":\"test.lat\",\"line\":2,\"column\":3,\"offset\":10,\"length\":1,\
+\"stack\":[5],\"pointer\":[2,1],\"notes\":[],\"help\":null}"
+        );
+    }
+
+    #[test]
+    fn leaving_the_grid() {
+        let (sources, err) = run("3 r 1 2 5 l");
+        let err = runtime_error(&err);
+
+        assert_eq!(err.msg, "Moving 5 cells left leaves the grid.");
+        assert_eq!((err.pos.line, err.pos.col), (1, 11));
+        assert_eq!(err.stack, [1, 2, 5]);
+        assert_eq!(err.mem_addr, (3, 0));
 
-                ip = function_ip - 1;
+        assert!(diag::render(&sources, &Error::RuntimeError(err.clone())).contains("= note: grid pointer: (3, 0)"));
+        assert!(diag::render_json(&sources, &Error::RuntimeError(err.clone())).contains("\"stack\":[1,2,5],\"pointer\":[3,0]"));
+    }
+
+    #[test]
+    fn string_literal_leaving_the_grid() {
+        let (_, err) = run("1 r \"abc\"l");
+        let err = runtime_error(&err);
+
+        assert_eq!(err.msg, "String literal of length 3 runs left off the grid.");
+        assert_eq!((err.pos.line, err.pos.col), (1, 5));
+        assert_eq!(err.stack, [] as [u64; 0]);
+        assert_eq!(err.mem_addr, (1, 0));
+    }
+
+    // Accepts `room` bytes, then fails like a pipe whose reader went away
+    struct ClosedPipe {
+        written: Vec<u8>,
+        room: usize
+    }
+
+    impl Write for ClosedPipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.written.len() >= self.room {
+                return Err(io::ErrorKind::BrokenPipe.into());
             }
+            let n = buf.len().min(self.room - self.written.len());
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
         }
 
-        ip += 1;
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
     }
 
-    Ok(())
+    #[test]
+    fn puts_stops_when_the_output_closes() {
+        let (_, program) = test_program("\"hi\" drop 0 1 - puts 7");
+
+        let mut output = ClosedPipe { written: Vec::new(), room: 4 };
+        let code = simulate_with(&program, &mut &b""[..], &mut output).unwrap();
+
+        assert_eq!(code, 7);
+        assert_eq!(output.written, b"hi\0\0");
+    }
 }
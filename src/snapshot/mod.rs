@@ -0,0 +1,140 @@
+use std::fs;
+use std::io::Write;
+use std::path::{ Path, PathBuf };
+use std::process::{ Command, Stdio };
+
+use super::Error;
+
+// Recorded behavior of a program, kept next to it as `NAME.expected`:
+//
+//   :i exit 0
+//   :b stdin 0
+//
+//   :b stdout 3
+//   42
+//
+//   :b stderr 0
+//
+// Each `:b` field holds exactly the given number of bytes followed by a
+// newline, so any output round-trips unchanged.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Snapshot {
+    // Fed to the program while it runs
+    pub stdin: Vec<u8>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub exit: i32
+}
+
+pub fn path_for(program: &Path) -> PathBuf {
+    program.with_extension("expected")
+}
+
+impl Snapshot {
+    // Runs `lattice sim` on the program with the given input and records
+    // what it printed and how it exited
+    pub fn record(lattice: &Path, program: &Path, stdin: &[u8]) -> Result<Snapshot, Error> {
+        let mut child = Command::new(lattice)
+            .arg("sim")
+            .arg(program)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| Error::without_pos(format!("Unable to run {}: {}", lattice.display(), err)))?;
+
+        // The program may exit without reading all of its input
+        let _ = child.stdin.take().map(|mut pipe| pipe.write_all(stdin));
+
+        let output = child.wait_with_output()
+            .map_err(|err| Error::without_pos(format!("Unable to run {}: {}", program.display(), err)))?;
+
+        Ok(Snapshot {
+            stdin: stdin.to_vec(),
+            stdout: output.stdout,
+            stderr: output.stderr,
+            exit: output.status.code().unwrap_or(1)
+        })
+    }
+
+    // Describes every field that differs from `expected`
+    pub fn diff(&self, expected: &Snapshot) -> Vec<String> {
+        let mut diffs: Vec<String> = Vec::new();
+
+        if self.exit != expected.exit {
+            diffs.push(format!("exit code: expected {}, got {}", expected.exit, self.exit));
+        }
+
+        for (name, actual, expected) in [("stdout", &self.stdout, &expected.stdout), ("stderr", &self.stderr, &expected.stderr)] {
+            if actual != expected {
+                diffs.push(format!("{}:\n  expected: {:?}\n  got:      {:?}",
+                    name, String::from_utf8_lossy(expected), String::from_utf8_lossy(actual)));
+            }
+        }
+
+        diffs
+    }
+
+    pub fn load(path: &Path) -> Result<Snapshot, Error> {
+        let bytes = fs::read(path)
+            .map_err(|err| Error::without_pos(format!("Unable to read {}: {}", path.display(), err)))?;
+
+        Snapshot::parse(&bytes)
+            .map_err(|msg| Error::without_pos(format!("Invalid snapshot {}: {}", path.display(), msg)))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        fs::write(path, self.to_bytes())
+            .map_err(|err| Error::without_pos(format!("Unable to write {}: {}", path.display(), err)))
+    }
+
+    pub fn parse(mut bytes: &[u8]) -> Result<Snapshot, String> {
+        let mut snapshot = Snapshot::default();
+
+        while !bytes.is_empty() {
+            let line_end = bytes.iter().position(|b| *b == b'\n').unwrap_or(bytes.len());
+            let line = std::str::from_utf8(&bytes[..line_end]).map_err(|_| "field header is not UTF-8".to_string())?;
+            bytes = &bytes[(line_end + 1).min(bytes.len())..];
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [] => continue,
+                [":i", "exit", value] => {
+                    snapshot.exit = value.parse().map_err(|_| format!("invalid exit code `{}`", value))?;
+                },
+                [":b", name, len] => {
+                    let len: usize = len.parse().map_err(|_| format!("invalid length `{}`", len))?;
+                    if bytes.len() < len {
+                        return Err(format!("`{}` is shorter than its length of {}", name, len));
+                    }
+
+                    let value = bytes[..len].to_vec();
+                    bytes = &bytes[len..];
+                    bytes = bytes.strip_prefix(b"\n").unwrap_or(bytes);
+
+                    match *name {
+                        "stdin" => snapshot.stdin = value,
+                        "stdout" => snapshot.stdout = value,
+                        "stderr" => snapshot.stderr = value,
+                        _ => return Err(format!("unknown field `{}`", name))
+                    }
+                },
+                _ => return Err(format!("invalid field `{}`", line))
+            }
+        }
+
+        Ok(snapshot)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!(":i exit {}\n", self.exit).into_bytes();
+
+        for (name, value) in [("stdin", &self.stdin), ("stdout", &self.stdout), ("stderr", &self.stderr)] {
+            out.extend_from_slice(format!(":b {} {}\n", name, value.len()).as_bytes());
+            out.extend_from_slice(value);
+            out.push(b'\n');
+        }
+
+        out
+    }
+}
@@ -0,0 +1,472 @@
+use std::fs::{ File, OpenOptions };
+use std::io::{ self, BufRead, IsTerminal, Read, Write };
+use std::process::{ Command, Stdio };
+use std::thread;
+use std::time::Duration;
+
+use super::{ Error, SourceMap, Token, TokenPos };
+use super::parse::{ Node, Program };
+use super::sim::Machine;
+
+const FRAME_TIME: Duration = Duration::from_millis(30);
+const SIZE_CHECK_FRAMES: u64 = 15;
+const MAX_SPEED: u64 = 1 << 20;
+// Characters per cell, including the space between cells
+const CELL_WIDTH: usize = 4;
+const STACK_WIDTH: usize = 24;
+const OUTPUT_LINES: usize = 4;
+
+const HELP: &str = "space run/pause  s step  +/- speed  arrows pan  f follow pointer  q quit";
+
+// Puts the terminal in non-canonical mode on an alternate screen, and puts it
+// back the way it was when dropped
+struct Terminal {
+    tty: File,
+    saved: String
+}
+
+impl Terminal {
+    fn open() -> Result<Terminal, Error> {
+        let tty = OpenOptions::new().read(true).write(true).open("/dev/tty")
+            .map_err(|err| Error::without_pos(format!("The TUI needs a terminal: {}", err)))?;
+
+        let saved = stty(&tty, &["-g"])?;
+        // Reads return immediately, with or without a key
+        stty(&tty, &["-icanon", "-echo", "-isig", "min", "0", "time", "0"])?;
+
+        let mut terminal = Terminal { tty, saved: saved.trim().to_string() };
+        let _ = write!(terminal.tty, "\x1b[?1049h\x1b[?25l");
+
+        Ok(terminal)
+    }
+
+    // Rows and columns
+    fn size(&self) -> (usize, usize) {
+        let size = stty(&self.tty, &["size"]).unwrap_or_default();
+        let mut numbers = size.split_whitespace().filter_map(|n| n.parse::<usize>().ok());
+
+        match (numbers.next(), numbers.next()) {
+            (Some(rows), Some(cols)) if rows > 0 && cols > 0 => (rows, cols),
+            _ => (24, 80)
+        }
+    }
+
+    fn keys(&mut self) -> Vec<u8> {
+        let mut keys = [0; 64];
+        match self.tty.read(&mut keys) {
+            Ok(n) => keys[..n].to_vec(),
+            Err(_) => Vec::new()
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let _ = write!(self.tty, "\x1b[?25h\x1b[?1049l");
+        let _ = self.tty.flush();
+        let saved = self.saved.clone();
+        let _ = stty(&self.tty, &[&saved]);
+    }
+}
+
+fn stty(tty: &File, args: &[&str]) -> Result<String, Error> {
+    let stdin = tty.try_clone()
+        .map_err(|err| Error::without_pos(format!("Unable to use the terminal: {}", err)))?;
+
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::from(stdin))
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|err| Error::without_pos(format!("Unable to run stty: {}", err)))?;
+
+    if !output.status.success() {
+        return Err(Error::without_pos(format!("`stty {}` failed.", args.join(" "))));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    Char(u8),
+}
+
+fn parse_keys(bytes: &[u8]) -> Vec<Key> {
+    let mut keys: Vec<Key> = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i..] {
+            [0x1b, b'[', b'A', ..] => keys.push(Key::Up),
+            [0x1b, b'[', b'B', ..] => keys.push(Key::Down),
+            [0x1b, b'[', b'C', ..] => keys.push(Key::Right),
+            [0x1b, b'[', b'D', ..] => keys.push(Key::Left),
+            [byte, ..] => {
+                keys.push(Key::Char(byte));
+                i += 1;
+                continue;
+            },
+            [] => break
+        }
+        i += 3;
+    }
+
+    keys
+}
+
+struct Viewer<'a> {
+    sources: &'a SourceMap,
+    file: String,
+    running: bool,
+    // Steps per frame while running
+    speed: u64,
+    steps: u64,
+    // Center of the view when panned away from the pointer
+    pan: Option<(u32, u32)>,
+    output: Vec<u8>,
+    error: Option<Error>
+}
+
+impl<'a> Viewer<'a> {
+    fn status(&self, machine: &Machine) -> String {
+        if self.error.is_some() {
+            String::from("error")
+        } else if machine.is_finished() {
+            format!("exited with {}", machine.exit_code())
+        } else if self.running {
+            format!("running, {} steps/frame", self.speed)
+        } else {
+            String::from("paused")
+        }
+    }
+
+    fn draw(&self, machine: &Machine, rows: usize, cols: usize) -> String {
+        let mut lines: Vec<String> = Vec::new();
+        let (px, py, _) = machine.mem_addr;
+
+        lines.push(format!(" {}  step {}  pointer ({}, {})  [{}]", self.file, self.steps, px, py, self.status(machine)));
+
+        // Title, source line and caret, output and help around the grid
+        let grid_rows = rows.saturating_sub(1 + 1 + 3 + 1 + OUTPUT_LINES + 1).max(1);
+        let (cx, cy) = self.pan.unwrap_or((px, py));
+        let top = cy.saturating_sub(grid_rows as u32 / 2).min(u32::MAX - (grid_rows as u32 - 1));
+        let label = (top as u64 + grid_rows as u64 - 1).to_string().len();
+
+        let grid_cols = (cols.saturating_sub(label + 1 + STACK_WIDTH) / CELL_WIDTH).max(1);
+        let left = cx.saturating_sub(grid_cols as u32 / 2).min(u32::MAX - (grid_cols as u32 - 1));
+
+        lines.push(format!("{:label$}  x {}..{}", "", left, left as u64 + grid_cols as u64 - 1, label = label));
+
+        // Top of the stack first
+        let mut stack: Vec<String> = vec![String::from("stack")];
+        stack.extend(machine.stack.iter().rev().map(|value| value.to_string()));
+        if stack.len() > grid_rows {
+            stack.truncate(grid_rows - 1);
+            stack.push(String::from("..."));
+        }
+
+        for row in 0..grid_rows {
+            let y = top + row as u32;
+            let mut line = format!("{:>label$} ", y, label = label);
+
+            for col in 0..grid_cols {
+                let x = left + col as u32;
+                let text = match machine.cell(x, y) {
+                    0 => String::from("."),
+                    value if value.to_string().len() < CELL_WIDTH => value.to_string(),
+                    _ => "*".repeat(CELL_WIDTH - 1)
+                };
+
+                if (x, y) == (px, py) {
+                    line.push_str(&format!("\x1b[7m{:>width$}\x1b[0m ", text, width = CELL_WIDTH - 1));
+                } else {
+                    line.push_str(&format!("{:>width$} ", text, width = CELL_WIDTH - 1));
+                }
+            }
+
+            if let Some(entry) = stack.get(row) {
+                let entry: String = entry.chars().take(STACK_WIDTH - 2).collect();
+                line.push_str(&format!("| {}", entry));
+            } else {
+                line.push('|');
+            }
+            lines.push(line);
+        }
+
+        match machine.pos() {
+            Some(pos) => {
+                let text = &self.sources.get(pos.file).text;
+                let source = text.lines().nth(pos.line - 1).unwrap_or("").replace('\t', " ");
+                let gutter = pos.line.to_string().len();
+
+                lines.push(String::new());
+                lines.push(format!("{} | {}", pos.line, source.trim_end()));
+                lines.push(format!("{:gutter$} | {:>col$}", "", "^", gutter = gutter, col = pos.col));
+            },
+            None => {
+                lines.push(String::new());
+                lines.push(String::from("(end of program)"));
+                lines.push(String::new());
+            }
+        }
+
+        lines.push(String::from("output:"));
+        let output = String::from_utf8_lossy(&self.output);
+        let output: Vec<&str> = output.lines().collect();
+        for line in &output[output.len().saturating_sub(OUTPUT_LINES)..] {
+            lines.push(format!("  {}", line.chars().map(|c| if c.is_control() { '?' } else { c }).collect::<String>()));
+        }
+        while lines.len() < rows - 1 {
+            lines.push(String::new());
+        }
+
+        lines.truncate(rows - 1);
+        lines.push(match &self.error {
+            Some(err) => err.to_string(),
+            None => HELP.to_string()
+        });
+
+        let mut frame = String::from("\x1b[H");
+        for (index, line) in lines.iter().enumerate() {
+            // Escape sequences take no room, so only plain lines are cut to fit
+            let line: String = match line.contains('\x1b') {
+                true => line.clone(),
+                false => line.chars().take(cols).collect()
+            };
+            frame.push_str(&line);
+            frame.push_str("\x1b[K");
+            if index + 1 < lines.len() {
+                frame.push_str("\r\n");
+            }
+        }
+        frame.push_str("\x1b[J");
+
+        frame
+    }
+
+    fn step(&mut self, machine: &mut Machine, input: &mut dyn BufRead) {
+        if self.error.is_some() || machine.is_finished() {
+            self.running = false;
+            return;
+        }
+
+        match machine.step(input, &mut self.output) {
+            Ok(()) => self.steps += 1,
+            Err(err) => {
+                self.error = Some(err);
+                self.running = false;
+            }
+        }
+    }
+}
+
+// The first `key` or `readln` in `nodes`
+fn find_input(nodes: &[Node]) -> Option<TokenPos> {
+    nodes.iter().find_map(|node| match node {
+        Node::Word(Token::Key, pos) | Node::Word(Token::ReadLine, pos) => Some(*pos),
+        Node::Word(..) => None,
+        Node::If(block) => find_input(&block.then_body)
+            .or_else(|| block.else_body.as_ref().and_then(|(_, body)| find_input(body))),
+        Node::While(block) => find_input(&block.cond).or_else(|| find_input(&block.body))
+    })
+}
+
+// Keys are read from the terminal, so a program reading stdin from the same
+// terminal would take keystrokes meant for the viewer; its input has to be
+// piped in instead
+pub fn check_input(program: &Program) -> Result<(), Error> {
+    match io::stdin().is_terminal() {
+        true => refuse_input(program),
+        false => Ok(())
+    }
+}
+
+// Fails at the first `key` or `readln`, in the main body or else in a function
+fn refuse_input(program: &Program) -> Result<(), Error> {
+    let pos = find_input(&program.body)
+        .or_else(|| program.functions.iter().find_map(|function| find_input(&function.body)));
+
+    match pos {
+        Some(pos) => Err(Error::new("`--tui` can't share the terminal with a program that reads input.", pos)
+            .with_help("Pipe the program's input in, e.g. `lattice sim --tui FILE < input.txt`.")),
+        None => Ok(())
+    }
+}
+
+// Runs the program while showing the grid around the pointer, the stack and
+// the current source line, until the user quits. Returns the program's exit
+// code, or 0 if it was quit before the program finished. The program's output is
+// shown in the viewer and printed to `output` when it closes; it reads its
+// input from `input`, while keys are read from the terminal (see `check_input`).
+pub fn run(machine: &mut Machine, sources: &SourceMap, file: &str, input: &mut dyn BufRead, output: &mut dyn Write) -> Result<i32, Error> {
+    let mut viewer = Viewer {
+        sources,
+        file: file.to_string(),
+        running: false,
+        speed: 1,
+        steps: 0,
+        pan: None,
+        output: Vec::new(),
+        error: None
+    };
+
+    {
+        let mut terminal = Terminal::open()?;
+        let mut size = terminal.size();
+        let mut frames: u64 = 0;
+
+        'viewer: loop {
+            let (px, py, _) = machine.mem_addr;
+
+            for key in parse_keys(&terminal.keys()) {
+                let (cx, cy) = viewer.pan.unwrap_or((px, py));
+
+                match key {
+                    Key::Char(b'q') | Key::Char(3) => break 'viewer,
+                    Key::Char(b' ') => viewer.running = !viewer.running,
+                    Key::Char(b's') => {
+                        viewer.running = false;
+                        viewer.step(machine, input);
+                    },
+                    Key::Char(b'+') | Key::Char(b'=') => viewer.speed = (viewer.speed * 2).min(MAX_SPEED),
+                    Key::Char(b'-') => viewer.speed = (viewer.speed / 2).max(1),
+                    Key::Char(b'f') => viewer.pan = None,
+                    Key::Up => viewer.pan = Some((cx, cy.saturating_sub(1))),
+                    Key::Down => viewer.pan = Some((cx, cy.saturating_add(1))),
+                    Key::Left => viewer.pan = Some((cx.saturating_sub(1), cy)),
+                    Key::Right => viewer.pan = Some((cx.saturating_add(1), cy)),
+                    Key::Char(_) => { }
+                }
+            }
+
+            if viewer.running {
+                for _ in 0..viewer.speed {
+                    viewer.step(machine, input);
+                    if !viewer.running {
+                        break;
+                    }
+                }
+            }
+
+            // Checking for resizes runs stty, so it isn't done every frame
+            frames += 1;
+            if frames % SIZE_CHECK_FRAMES == 0 {
+                size = terminal.size();
+            }
+
+            let frame = viewer.draw(machine, size.0, size.1);
+            let _ = terminal.tty.write_all(frame.as_bytes());
+            let _ = terminal.tty.flush();
+
+            thread::sleep(FRAME_TIME);
+        }
+    }
+
+    let _ = output.write_all(&viewer.output);
+    let _ = output.flush();
+
+    match viewer.error {
+        Some(err) => Err(err),
+        None if machine.is_finished() => Ok(machine.exit_code()),
+        None => Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_program;
+
+    fn viewer(sources: &SourceMap) -> Viewer<'_> {
+        Viewer {
+            sources,
+            file: String::from("test.lat"),
+            running: false,
+            speed: 1,
+            steps: 0,
+            pan: None,
+            output: Vec::new(),
+            error: None
+        }
+    }
+
+    // The frame's lines without escape sequences
+    fn draw(src: &str, pan: Option<(u32, u32)>) -> Vec<String> {
+        let (sources, program) = test_program(src);
+        let mut machine = Machine::new(&program);
+        machine.run(&mut &b""[..], &mut Vec::new()).unwrap();
+
+        let mut viewer = viewer(&sources);
+        viewer.pan = pan;
+
+        let mut frame = viewer.draw(&machine, 24, 80);
+        for escape in &["\x1b[H", "\x1b[K", "\x1b[J", "\x1b[7m", "\x1b[0m"] {
+            frame = frame.replace(escape, "");
+        }
+        frame.split("\r\n").map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn arrow_keys() {
+        assert_eq!(parse_keys(b"\x1b[A\x1b[Bq\x1b[C\x1b[D"), vec![
+            Key::Up, Key::Down, Key::Char(b'q'), Key::Right, Key::Left
+        ]);
+        // An escape cut off by the end of a read is passed through as is
+        assert_eq!(parse_keys(b" \x1b["), vec![Key::Char(b' '), Key::Char(0x1b), Key::Char(b'[')]);
+    }
+
+    #[test]
+    fn view_stays_on_the_grid() {
+        // 24 rows and 80 columns leave room for 13 rows, and for 11 cells
+        // next to ten digit row labels or 13 next to two digit ones
+        let lines = draw("", Some((u32::MAX, u32::MAX)));
+        assert_eq!(lines[1].trim(), "x 4294967285..4294967295");
+        assert!(lines[2].starts_with("4294967283 "));
+        assert!(lines[14].starts_with("4294967295 "));
+
+        let lines = draw("", Some((0, 0)));
+        assert_eq!(lines[1].trim(), "x 0..12");
+        assert!(lines[2].starts_with(" 0 "));
+        assert!(lines[14].starts_with("12 "));
+    }
+
+    #[test]
+    fn view_follows_the_pointer() {
+        let lines = draw("4294967295 r 7 . 0 d", None);
+        assert!(lines[0].contains("pointer (4294967295, 0)"));
+        assert_eq!(lines[1].trim(), "x 4294967283..4294967295");
+        assert!(lines[2].starts_with(" 0 "));
+        assert!(lines[2].ends_with("  7 | stack"));
+    }
+
+    #[test]
+    fn long_stacks_are_cut_off() {
+        let numbers: Vec<String> = (1..=20).map(|n| n.to_string()).collect();
+        let lines = draw(&numbers.join(" "), None);
+
+        assert!(lines[2].ends_with("| stack"));
+        assert!(lines[3].ends_with("| 20"));
+        assert!(lines[13].ends_with("| 10"));
+        assert!(lines[14].ends_with("| ..."));
+        assert!(lines[15].is_empty());
+    }
+
+    #[test]
+    fn programs_reading_input_are_refused() {
+        let (_, program) = test_program("fn ask\n    key\nend\n1 if\n    readln drop\nend");
+        let err = refuse_input(&program).unwrap_err();
+        assert_eq!(err.msg(), "`--tui` can't share the terminal with a program that reads input.");
+        assert_eq!(err.pos().map(|pos| (pos.line, pos.col)), Some((5, 5)));
+
+        let (_, program) = test_program("fn ask\n    key\nend\nask drop");
+        assert_eq!(refuse_input(&program).unwrap_err().pos().map(|pos| (pos.line, pos.col)), Some((2, 5)));
+
+        let (_, program) = test_program("1 print");
+        assert!(refuse_input(&program).is_ok());
+    }
+}
@@ -0,0 +1,145 @@
+// Runs every `tests/*.lat` program through the simulator and as a compiled
+// executable, checking that both match the stdout and exit code recorded in
+// the program's `.expected` file. Programs may be expected to fail; only the
+// simulator's stderr is compared, since the compiled runtime reports errors
+// without positions.
+
+use std::fs;
+use std::io::Write;
+use std::path::{ Path, PathBuf };
+use std::process::{ Command, Stdio };
+
+use lattice_lib::*;
+use lattice_lib::snapshot::{ self, Snapshot };
+
+fn load_program(path: &Path) -> Result<(parse::Program, SourceMap), String> {
+    let mut sources = SourceMap::default();
+    let render = |sources: &SourceMap, errors: &[Error]| {
+        errors.iter().map(|err| diag::render(sources, err)).collect::<Vec<_>>().join("\n")
+    };
+
+    let file = load_file(&mut sources, path.to_str().unwrap()).map_err(|err| render(&sources, &[err]))?;
+    let program = parse::parse_file(&sources, file).map_err(|errors| render(&sources, &errors))?;
+    check::check_program(&program).map_err(|errors| render(&sources, &errors))?;
+
+    Ok((program, sources))
+}
+
+// Reports a runtime error the way `lattice sim` does
+fn simulate(program: &parse::Program, sources: &SourceMap, stdin: &[u8]) -> (Vec<u8>, Vec<u8>, i32) {
+    let mut stdout: Vec<u8> = Vec::new();
+
+    match sim::simulate_with(program, &mut &stdin[..], &mut stdout) {
+        Ok(exit) => (stdout, Vec::new(), exit),
+        Err(err) => (stdout, format!("{}\n", diag::render(sources, &err)).into_bytes(), 1)
+    }
+}
+
+fn run_compiled(program: &parse::Program, path: &Path, stdin: &[u8]) -> Result<(Vec<u8>, i32), String> {
+    let output = PathBuf::from(env!("CARGO_TARGET_TMPDIR"))
+        .join("differential")
+        .join(path.file_stem().unwrap());
+
+    let options = com::CompileOptions { output: Some(output), ..Default::default() };
+    let executable = com::compile(program, path.to_str().unwrap(), &options).map_err(|err| err.to_string())?;
+
+    let mut child = Command::new(&executable)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("failed to run {}: {}", executable.display(), err))?;
+
+    let _ = child.stdin.take().unwrap().write_all(stdin);
+    let result = child.wait_with_output().map_err(|err| err.to_string())?;
+
+    #[cfg(unix)]
+    let exit = {
+        use std::os::unix::process::ExitStatusExt;
+        result.status.code().or_else(|| result.status.signal().map(|s| 128 + s)).unwrap_or(1)
+    };
+    #[cfg(not(unix))]
+    let exit = result.status.code().unwrap_or(1);
+
+    Ok((result.stdout, exit))
+}
+
+fn compare(backend: &str, expected: &Snapshot, stdout: &[u8], exit: i32) -> Option<String> {
+    if stdout == expected.stdout.as_slice() && exit == expected.exit {
+        return None;
+    }
+
+    Some(format!(
+        "  {}:\n    expected exit {}, stdout {:?}\n    got      exit {}, stdout {:?}",
+        backend, expected.exit, String::from_utf8_lossy(&expected.stdout),
+        exit, String::from_utf8_lossy(stdout)
+    ))
+}
+
+fn compare_stderr(expected: &Snapshot, stderr: &[u8]) -> Option<String> {
+    if stderr == expected.stderr.as_slice() {
+        return None;
+    }
+
+    Some(format!(
+        "  sim:\n    expected stderr {:?}\n    got      stderr {:?}",
+        String::from_utf8_lossy(&expected.stderr), String::from_utf8_lossy(stderr)
+    ))
+}
+
+#[test]
+fn sim_and_com_match_expected_output() {
+    let mut programs: Vec<PathBuf> = fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "lat"))
+        .collect();
+    programs.sort();
+
+    assert!(!programs.is_empty(), "no test programs found");
+
+    let mut failures: Vec<String> = Vec::new();
+
+    for path in &programs {
+        let name = path.file_name().unwrap().to_string_lossy();
+
+        let expected = match Snapshot::load(&snapshot::path_for(path)) {
+            Ok(expected) => expected,
+            Err(err) => {
+                failures.push(format!("{}: {}", name, err));
+                continue;
+            }
+        };
+
+        // Errors name the file the way `lattice test` does, relative to the
+        // package, which is where cargo runs tests from
+        let relative = path.strip_prefix(env!("CARGO_MANIFEST_DIR")).unwrap();
+        let (program, sources) = match load_program(relative) {
+            Ok(loaded) => loaded,
+            Err(err) => {
+                failures.push(format!("{}: failed to compile\n{}", name, err));
+                continue;
+            }
+        };
+
+        let (sim_stdout, sim_stderr, sim_exit) = simulate(&program, &sources, &expected.stdin);
+        let mut mismatches: Vec<String> = compare("sim", &expected, &sim_stdout, sim_exit).into_iter().collect();
+        mismatches.extend(compare_stderr(&expected, &sim_stderr));
+
+        match run_compiled(&program, path, &expected.stdin) {
+            Ok((com_stdout, com_exit)) => {
+                mismatches.extend(compare("com", &expected, &com_stdout, com_exit));
+                if com_exit != sim_exit {
+                    mismatches.push(format!("  sim exited with {} but com exited with {}", sim_exit, com_exit));
+                }
+            },
+            Err(err) => mismatches.push(format!("  com: {}", err))
+        }
+
+        if !mismatches.is_empty() {
+            failures.push(format!("{}:\n{}", name, mismatches.join("\n")));
+        }
+    }
+
+    assert!(failures.is_empty(), "{} of {} programs failed:\n{}", failures.len(), programs.len(), failures.join("\n"));
+}